@@ -0,0 +1,240 @@
+use super::test_coverage_container::{CoverageCount, TestCoverageContainer, TestCoverageSource};
+use crate::util::escape_markup;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Generates a Cobertura XML report (as read by Azure DevOps, GitLab, Jenkins, etc.) from the collected coverage.
+/// Every rhai source is a class, grouped into a package per folder, with the hits of each line and the conditions
+/// (branch outcomes) taken on it.
+pub fn generate_report(test_coverage_container: &TestCoverageContainer) -> String {
+    let sources = test_coverage_container.get_sorted_sources();
+    let mut packages = BTreeMap::<String, Vec<(&TestCoverageSource, Vec<Line>)>>::new();
+
+    for source in sources {
+        let lines = get_lines(source);
+        let package = Path::new(&source.name)
+            .parent()
+            .map(|parent| parent.display().to_string())
+            .filter(|parent| !parent.is_empty())
+            .unwrap_or(".".to_string());
+        packages.entry(package).or_default().push((source, lines));
+    }
+
+    let (lines_total, branches_total) =
+        get_rates(packages.values().flatten().flat_map(|(_, lines)| lines));
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_millis())
+        .unwrap_or_default();
+    let current_dir = std::env::current_dir()
+        .map(|current_dir| current_dir.display().to_string())
+        .unwrap_or(".".to_string());
+
+    let mut output = String::new();
+    writeln!(output, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        output,
+        r#"<!DOCTYPE coverage SYSTEM "http://cobertura.sourceforge.net/xml/coverage-04.dtd">"#
+    )
+    .unwrap();
+    writeln!(
+        output,
+        r#"<coverage lines-valid="{}" lines-covered="{}" line-rate="{}" branches-valid="{}" branches-covered="{}" branch-rate="{}" timestamp="{}" complexity="0" version="0.1">"#,
+        lines_total.total,
+        lines_total.hit,
+        get_rate(lines_total),
+        branches_total.total,
+        branches_total.hit,
+        get_rate(branches_total),
+        timestamp
+    )
+    .unwrap();
+    writeln!(output, "  <sources>").unwrap();
+    writeln!(
+        output,
+        "    <source>{}</source>",
+        escape_markup(&current_dir)
+    )
+    .unwrap();
+    writeln!(output, "  </sources>").unwrap();
+    writeln!(output, "  <packages>").unwrap();
+
+    for (package, classes) in packages {
+        let (package_lines, package_branches) =
+            get_rates(classes.iter().flat_map(|(_, lines)| lines));
+        writeln!(
+            output,
+            r#"    <package name="{}" line-rate="{}" branch-rate="{}" complexity="0">"#,
+            escape_markup(&package),
+            get_rate(package_lines),
+            get_rate(package_branches)
+        )
+        .unwrap();
+        writeln!(output, "      <classes>").unwrap();
+
+        for (source, lines) in classes {
+            write_class(&mut output, source, &lines);
+        }
+
+        writeln!(output, "      </classes>").unwrap();
+        writeln!(output, "    </package>").unwrap();
+    }
+
+    writeln!(output, "  </packages>").unwrap();
+    writeln!(output, "</coverage>").unwrap();
+
+    output
+}
+
+fn write_class(output: &mut String, source: &TestCoverageSource, lines: &[Line]) {
+    let (class_lines, class_branches) = get_rates(lines);
+    writeln!(
+        output,
+        r#"        <class name="{}" filename="{}" line-rate="{}" branch-rate="{}" complexity="0">"#,
+        escape_markup(&source.name),
+        escape_markup(&source.file_path),
+        get_rate(class_lines),
+        get_rate(class_branches)
+    )
+    .unwrap();
+
+    // Functions (only their first line is known, which was hit as many times as the function was called)
+    let mut functions = source.functions.values().collect::<Vec<_>>();
+    functions.sort_by_key(|function| function.line_number);
+    writeln!(output, "          <methods>").unwrap();
+    for function in functions {
+        let rate = if function.hits.is_hit() { "1" } else { "0" };
+        writeln!(
+            output,
+            r#"            <method name="{}" signature="" line-rate="{}" branch-rate="{}" complexity="0">"#,
+            escape_markup(&function.function_name),
+            rate,
+            rate
+        )
+        .unwrap();
+        writeln!(
+            output,
+            r#"              <lines><line number="{}" hits="{}"/></lines>"#,
+            function.line_number, function.hits.count
+        )
+        .unwrap();
+        writeln!(output, "            </method>").unwrap();
+    }
+    writeln!(output, "          </methods>").unwrap();
+
+    writeln!(output, "          <lines>").unwrap();
+    for line in lines {
+        if line.conditions.total == 0 {
+            writeln!(
+                output,
+                r#"            <line number="{}" hits="{}" branch="false"/>"#,
+                line.line_number, line.hits
+            )
+            .unwrap();
+            continue;
+        }
+
+        writeln!(
+            output,
+            r#"            <line number="{}" hits="{}" branch="true" condition-coverage="{}% ({}/{})">"#,
+            line.line_number,
+            line.hits,
+            line.conditions.percent().round(),
+            line.conditions.hit,
+            line.conditions.total
+        )
+        .unwrap();
+        writeln!(output, "              <conditions>").unwrap();
+        for (number, condition) in line.branches.iter().enumerate() {
+            writeln!(
+                output,
+                r#"                <condition number="{}" type="jump" coverage="{}%"/>"#,
+                number,
+                condition.percent().round()
+            )
+            .unwrap();
+        }
+        writeln!(output, "              </conditions>").unwrap();
+        writeln!(output, "            </line>").unwrap();
+    }
+    writeln!(output, "          </lines>").unwrap();
+    writeln!(output, "        </class>").unwrap();
+}
+
+/// A line of a source that holds statements and/or branches
+struct Line {
+    line_number: i64,
+    /// A line can hold several statements, it was hit as many times as the most executed of them (or of its branch
+    /// outcomes)
+    hits: u64,
+    /// The outcomes taken of every branch on the line
+    branches: Vec<CoverageCount>,
+    /// The outcomes taken of all the branches on the line together
+    conditions: CoverageCount,
+}
+
+impl Line {
+    fn new(line_number: i64) -> Self {
+        Self {
+            line_number,
+            hits: 0,
+            branches: Vec::new(),
+            conditions: CoverageCount::default(),
+        }
+    }
+}
+
+fn get_lines(source: &TestCoverageSource) -> Vec<Line> {
+    let mut lines = BTreeMap::<i64, Line>::new();
+
+    for statement in source.statements.values() {
+        let line = lines
+            .entry(statement.line_number)
+            .or_insert_with(|| Line::new(statement.line_number));
+        line.hits = line.hits.max(statement.hits.count);
+    }
+
+    let mut branches = source.branches.values().collect::<Vec<_>>();
+    branches.sort_by_key(|branch| (branch.line_number, branch.column));
+    for branch in branches {
+        let outcomes = CoverageCount {
+            hit: branch
+                .outcomes
+                .iter()
+                .filter(|outcome| outcome.hits.is_hit())
+                .count(),
+            total: branch.outcomes.len(),
+        };
+        let line = lines
+            .entry(branch.line_number)
+            .or_insert_with(|| Line::new(branch.line_number));
+        for outcome in &branch.outcomes {
+            line.hits = line.hits.max(outcome.hits.count);
+        }
+        line.branches.push(outcomes);
+        line.conditions.hit += outcomes.hit;
+        line.conditions.total += outcomes.total;
+    }
+
+    lines.into_values().collect()
+}
+
+/// The lines and branch outcomes covered of a group of lines
+fn get_rates<'a>(lines: impl IntoIterator<Item = &'a Line>) -> (CoverageCount, CoverageCount) {
+    let mut line_count = CoverageCount::default();
+    let mut branch_count = CoverageCount::default();
+    for line in lines {
+        line_count.total += 1;
+        line_count.hit += (line.hits > 0) as usize;
+        branch_count.total += line.conditions.total;
+        branch_count.hit += line.conditions.hit;
+    }
+    (line_count, branch_count)
+}
+
+/// Cobertura rates are between 0 and 1, where nothing to cover counts as fully covered
+fn get_rate(count: CoverageCount) -> String {
+    format!("{:.4}", count.percent() / 100.0)
+}
@@ -0,0 +1,81 @@
+use super::{
+    file_coverage_module_resolver::get_source_name, instrumentation::instrument_source,
+    test_coverage_container::TestCoverageContainer,
+};
+use glob::glob;
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+/// Registers the coverage of every script matching the collectCoverageFrom glob patterns, without running any of it.
+/// This way scripts that no test imports are reported too (at 0%), instead of being left out of the coverage.
+///
+/// Test files are never collected, as they aren't instrumented when they run.
+/// Returns the registered coverage along with a warning for every script that couldn't be collected.
+pub fn collect_coverage_from(
+    patterns: &[String],
+    base_path: &str,
+    test_files: &[String],
+) -> Result<(TestCoverageContainer, Vec<String>), String> {
+    let test_coverage_container = Arc::new(Mutex::new(TestCoverageContainer::new()));
+    let test_files = test_files
+        .iter()
+        .filter_map(|test_file| fs::canonicalize(test_file).ok())
+        .collect::<Vec<_>>();
+    let mut warnings = Vec::new();
+
+    for pattern in patterns {
+        let entries = glob(pattern).map_err(|error| {
+            format!(
+                "collectCoverageFrom pattern '{}' is not a valid glob pattern. Error: {}",
+                pattern, error
+            )
+        })?;
+
+        for file_path in entries.filter_map(Result::ok) {
+            let is_test_file = fs::canonicalize(&file_path)
+                .map(|file_path| test_files.contains(&file_path))
+                .unwrap_or_default();
+            if is_test_file || !file_path.is_file() {
+                continue;
+            }
+
+            let contents = match fs::read_to_string(&file_path) {
+                Ok(contents) => contents,
+                Err(error) => {
+                    warnings.push(format!(
+                        "Unable to collect coverage from {}. Error: {}",
+                        file_path.display(),
+                        error
+                    ));
+                    continue;
+                }
+            };
+
+            let source_name = get_source_name(Path::new(base_path), &file_path);
+            if let Err(error) =
+                instrument_source(&contents, &source_name, test_coverage_container.clone())
+            {
+                warnings.push(format!(
+                    "Unable to collect coverage from {}. Error: {}",
+                    file_path.display(),
+                    error
+                ));
+                continue;
+            }
+            test_coverage_container
+                .lock()
+                .unwrap()
+                .set_source_file_path(&source_name, file_path.display().to_string());
+        }
+    }
+
+    let test_coverage_container = Arc::try_unwrap(test_coverage_container)
+        .expect("Collected coverage is still in use")
+        .into_inner()
+        .unwrap();
+
+    Ok((test_coverage_container, warnings))
+}
@@ -0,0 +1,126 @@
+use super::test_coverage_container::{CoverageCount, CoverageSummary, TestCoverageContainer};
+use glob::Pattern;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// The key of the thresholds that apply to every file not matched by one of the glob patterns
+const GLOBAL_KEY: &str = "global";
+
+/// Minimum coverage percentages, any that aren't set aren't checked
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct CoverageThresholdValues {
+    pub statements: Option<f64>,
+    pub branches: Option<f64>,
+    pub functions: Option<f64>,
+}
+
+/// `global` thresholds plus thresholds per file glob pattern, e.g. `{ "global": {...}, "lib/**/*.rhai": {...} }`
+pub type CoverageThreshold = BTreeMap<String, CoverageThresholdValues>;
+
+/// Checks the collected coverage against the configured thresholds.
+/// Files matching a glob pattern are each held to its thresholds, while the rest are held to the global thresholds together (like Jest).
+/// Returns a message for every threshold that wasn't met.
+pub fn check_coverage_thresholds(
+    coverage_threshold: &CoverageThreshold,
+    test_coverage_container: &TestCoverageContainer,
+) -> Result<Vec<String>, String> {
+    let mut patterns = Vec::new();
+    for (key, thresholds) in coverage_threshold {
+        if key == GLOBAL_KEY {
+            continue;
+        }
+
+        let pattern = Pattern::new(normalize_path(key)).map_err(|error| {
+            format!(
+                "coverageThreshold key '{}' is not a valid glob pattern. Error: {}",
+                key, error
+            )
+        })?;
+        patterns.push((key, pattern, thresholds));
+    }
+
+    let mut failures = Vec::new();
+    let mut global_summary = CoverageSummary::default();
+    let mut global_sources = Vec::new();
+
+    for source in test_coverage_container.get_sorted_sources() {
+        let summary = source.summary();
+        let matching_patterns = patterns
+            .iter()
+            .filter(|(_, pattern, _)| pattern.matches(normalize_path(&source.file_path)))
+            .collect::<Vec<_>>();
+
+        if matching_patterns.is_empty() {
+            global_summary.add(&summary);
+            global_sources.push((source.file_path.as_str(), summary));
+        }
+
+        for (_, _, thresholds) in matching_patterns {
+            failures.extend(check_summary(
+                thresholds,
+                &summary,
+                &format!("for {}", source.file_path),
+                &[],
+            ));
+        }
+    }
+
+    if let Some(thresholds) = coverage_threshold.get(GLOBAL_KEY) {
+        failures.extend(check_summary(
+            thresholds,
+            &global_summary,
+            "globally",
+            &global_sources,
+        ));
+    }
+
+    Ok(failures)
+}
+
+/// Checks a summary against its thresholds. When it's the summary of several sources combined, the sources that are
+/// below a threshold that wasn't met are listed under it.
+fn check_summary(
+    thresholds: &CoverageThresholdValues,
+    summary: &CoverageSummary,
+    subject: &str,
+    sources: &[(&str, CoverageSummary)],
+) -> Vec<String> {
+    [
+        ("statements", thresholds.statements),
+        ("branches", thresholds.branches),
+        ("functions", thresholds.functions),
+    ]
+    .into_iter()
+    .enumerate()
+    .filter_map(|(index, (kind, threshold))| {
+        let threshold = threshold?;
+        let percent = get_counts(summary)[index].percent();
+        if percent >= threshold {
+            return None;
+        }
+
+        let mut failure = format!(
+            "Coverage threshold for {} ({}%) not met {}: {:.2}%",
+            kind, threshold, subject, percent
+        );
+        for (file_path, source_summary) in sources {
+            let source_percent = get_counts(source_summary)[index].percent();
+            if source_percent < threshold {
+                write!(failure, "\n  {}: {:.2}%", file_path, source_percent).unwrap();
+            }
+        }
+        Some(failure)
+    })
+    .collect()
+}
+
+/// The statements, branches and functions counts, in the order their thresholds are checked
+fn get_counts(summary: &CoverageSummary) -> [CoverageCount; 3] {
+    [summary.statements, summary.branches, summary.functions]
+}
+
+/// Paths of imported modules and glob patterns from the config may or may not start with "./"
+fn normalize_path(path: &str) -> &str {
+    path.trim_start_matches("./")
+}
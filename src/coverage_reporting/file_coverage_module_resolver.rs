@@ -1,9 +1,9 @@
-use super::{instrumentation::instrument_line, test_coverage_container::TestCoverageContainer};
+use super::{instrumentation::instrument_source, test_coverage_container::TestCoverageContainer};
 use rhai::{Engine, EvalAltResult, Module, ModuleResolver, Position, Scope};
 use std::{
     collections::BTreeMap,
     fs,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
     sync::{Arc, Mutex},
 };
 
@@ -66,13 +66,21 @@ impl ModuleResolver for FileCoverageModuleResolver {
         let mut contents = fs::read_to_string(file_path.clone())
             .map_err(|_| Box::new(EvalAltResult::ErrorModuleNotFound(path.to_string(), pos)))?;
 
-        // Go line-by-line and instrument the code with coverage tracking functions
-        contents = contents
-            .lines()
-            .enumerate()
-            .map(|(i, line)| instrument_line(i, line, path, self.test_coverage_container.clone()))
-            .collect::<Vec<_>>()
-            .join("\n");
+        let source_name = get_source_name(&self.base_path, &file_path);
+        self.test_coverage_container
+            .lock()
+            .unwrap()
+            .set_source_file_path(&source_name, file_path.display().to_string());
+
+        // Instrument the code with coverage tracking functions. If it doesn't parse, it's left as is so that compiling it
+        // below reports the error
+        if let Ok(instrumented_contents) = instrument_source(
+            &contents,
+            &source_name,
+            self.test_coverage_container.clone(),
+        ) {
+            contents = instrumented_contents;
+        }
 
         // Now we can compile the AST
         let mut ast = engine.compile(&contents).map_err(|err| {
@@ -96,3 +104,23 @@ impl ModuleResolver for FileCoverageModuleResolver {
         Ok(m)
     }
 }
+
+/// The name a module's coverage is reported under: its path relative to the base path, without the `.rhai` extension.
+/// A module gets the same name however it's imported (e.g. `lib` or `./lib`), or when it's collected by
+/// collectCoverageFrom without being imported at all.
+pub fn get_source_name(base_path: &Path, file_path: &Path) -> String {
+    let without_current_dir = |path: &Path| {
+        path.components()
+            .filter(|component| *component != Component::CurDir)
+            .collect::<PathBuf>()
+    };
+    let base_path = without_current_dir(base_path);
+    let file_path = without_current_dir(file_path);
+
+    file_path
+        .strip_prefix(&base_path)
+        .unwrap_or(&file_path)
+        .with_extension("")
+        .display()
+        .to_string()
+}
@@ -0,0 +1,309 @@
+use super::test_coverage_container::{
+    CoverageCount, CoverageSummary, TestCoverageContainer, TestCoverageSource,
+};
+use crate::util::escape_markup;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+
+/// The page with the percentages of every file, which every other page links back to
+const INDEX_FILE_NAME: &str = "index.html";
+
+const STYLE: &str = r#"
+body { font-family: -apple-system, "Segoe UI", Helvetica, Arial, sans-serif; margin: 2em; color: #333; }
+h1 { font-size: 1.4em; font-weight: normal; }
+a { color: #0074d9; }
+.summary { display: flex; gap: 2em; margin-bottom: 1.5em; }
+.summary .count { color: #777; }
+table { border-collapse: collapse; }
+table.files th, table.files td { border-bottom: 1px solid #ddd; padding: 0.4em 1em; text-align: left; }
+table.source { width: 100%; font-family: Menlo, Consolas, monospace; font-size: 0.85em; }
+table.source td { padding: 0 0.5em; vertical-align: top; }
+table.source pre { margin: 0; white-space: pre-wrap; }
+.line-number { color: #999; text-align: right; user-select: none; }
+.hits { color: #777; text-align: right; white-space: nowrap; }
+.notes { white-space: nowrap; }
+.notes .covered { color: #3c763d; }
+.notes .uncovered { color: #a33; }
+.high { background: #e6f5d0; }
+.medium { background: #fff4c2; }
+.low { background: #fce1e5; }
+tr.covered .line-number, tr.covered pre { background: #e6f5d0; }
+tr.partial .line-number, tr.partial pre { background: #fff4c2; }
+tr.uncovered .line-number, tr.uncovered pre { background: #fce1e5; }
+"#;
+
+/// Generates a static HTML site from the collected coverage: an index page with the percentages of every file, and a
+/// page per file showing its source with the covered, partially covered and uncovered lines highlighted. Every function
+/// and branch outcome is noted next to its line, highlighted by whether it was called or taken, and every line shows
+/// how many times it was executed.
+///
+/// Returns the file name and contents of every page, starting with the index page.
+pub fn generate_report(test_coverage_container: &TestCoverageContainer) -> Vec<(String, String)> {
+    let sources = test_coverage_container.get_sorted_sources();
+    let mut pages = vec![(INDEX_FILE_NAME.to_string(), generate_index(&sources))];
+
+    for source in sources {
+        pages.push((
+            get_page_file_name(&source.name),
+            generate_source_page(source),
+        ));
+    }
+
+    pages
+}
+
+fn generate_index(sources: &[&TestCoverageSource]) -> String {
+    let mut total = CoverageSummary::default();
+    let mut rows = String::new();
+
+    for source in sources {
+        let summary = source.summary();
+        total.add(&summary);
+
+        write!(
+            rows,
+            r#"<tr><td><a href="{}">{}</a></td>"#,
+            escape_markup(&get_page_file_name(&source.name)),
+            escape_markup(&source.name)
+        )
+        .unwrap();
+        for count in [summary.statements, summary.branches, summary.functions] {
+            write_count_cells(&mut rows, count);
+        }
+        writeln!(rows, "</tr>").unwrap();
+    }
+
+    let mut body = String::new();
+    writeln!(body, "<h1>All files</h1>").unwrap();
+    write_summary(&mut body, &total);
+    writeln!(
+        body,
+        r#"<table class="files"><thead><tr><th>File</th><th colspan="2">Statements</th><th colspan="2">Branches</th><th colspan="2">Functions</th></tr></thead>"#
+    )
+    .unwrap();
+    writeln!(body, "<tbody>\n{}</tbody></table>", rows).unwrap();
+
+    generate_page("Coverage report", &body)
+}
+
+fn generate_source_page(source: &TestCoverageSource) -> String {
+    let mut body = String::new();
+    writeln!(
+        body,
+        r#"<h1><a href="{}">All files</a> / {}</h1>"#,
+        INDEX_FILE_NAME,
+        escape_markup(&source.name)
+    )
+    .unwrap();
+    writeln!(body, "<p>{}</p>", escape_markup(&source.file_path)).unwrap();
+    write_summary(&mut body, &source.summary());
+
+    let contents = match fs::read_to_string(&source.file_path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            writeln!(
+                body,
+                "<p>Unable to read the source of {}. Error: {}</p>",
+                escape_markup(&source.file_path),
+                escape_markup(&error.to_string())
+            )
+            .unwrap();
+            return generate_page(&source.name, &body);
+        }
+    };
+
+    let lines = get_line_coverage(source);
+    writeln!(body, r#"<table class="source"><tbody>"#).unwrap();
+    for (index, code) in contents.lines().enumerate() {
+        let line_number = index as i64 + 1;
+        let (class, hits, notes) = match lines.get(&line_number) {
+            Some(line) => (line.get_class(), line.get_hits_label(), line.get_notes()),
+            None => ("", String::new(), String::new()),
+        };
+
+        writeln!(
+            body,
+            r#"<tr class="{}"><td class="line-number">{}</td><td class="hits">{}</td><td><pre>{}</pre></td><td class="notes">{}</td></tr>"#,
+            class,
+            line_number,
+            hits,
+            escape_markup(code),
+            notes
+        )
+        .unwrap();
+    }
+    writeln!(body, "</tbody></table>").unwrap();
+
+    generate_page(&source.name, &body)
+}
+
+/// What was covered on a single line of a source
+#[derive(Default)]
+struct LineCoverage {
+    hit: usize,
+    total: usize,
+    /// How many times the most executed statement on the line was executed
+    max_count: u64,
+    /// The functions and branch outcomes on the line, and whether each of them was called or taken
+    notes: Vec<(String, bool)>,
+}
+
+impl LineCoverage {
+    fn get_class(&self) -> &'static str {
+        let has_uncovered_notes = self.notes.iter().any(|(_, is_covered)| !is_covered);
+        if self.total == 0 && self.notes.is_empty() {
+            ""
+        } else if self.hit == 0 && self.total > 0 {
+            "uncovered"
+        } else if self.hit < self.total || has_uncovered_notes {
+            "partial"
+        } else {
+            "covered"
+        }
+    }
+
+    /// Lines without any statement don't show a count
+    fn get_hits_label(&self) -> String {
+        if self.total == 0 {
+            String::new()
+        } else {
+            format!("{}x", self.max_count)
+        }
+    }
+
+    /// The notes as HTML, highlighted by whether they were covered
+    fn get_notes(&self) -> String {
+        self.notes
+            .iter()
+            .map(|(note, is_covered)| {
+                format!(
+                    r#"<span class="{}">{}</span>"#,
+                    if *is_covered { "covered" } else { "uncovered" },
+                    escape_markup(note)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+fn get_line_coverage(source: &TestCoverageSource) -> BTreeMap<i64, LineCoverage> {
+    let mut lines = BTreeMap::<i64, LineCoverage>::new();
+
+    for statement in source.statements.values() {
+        let line = lines.entry(statement.line_number).or_default();
+        line.total += 1;
+        line.hit += statement.hits.is_hit() as usize;
+        line.max_count = line.max_count.max(statement.hits.count);
+    }
+
+    let mut functions = source.functions.values().collect::<Vec<_>>();
+    functions.sort_by_key(|function| function.line_number);
+    for function in functions {
+        let note = if function.hits.is_hit() {
+            format!(
+                "{}() called {}x",
+                function.function_name, function.hits.count
+            )
+        } else {
+            format!("{}() never called", function.function_name)
+        };
+        lines
+            .entry(function.line_number)
+            .or_default()
+            .notes
+            .push((note, function.hits.is_hit()));
+    }
+
+    let mut branches = source.branches.values().collect::<Vec<_>>();
+    branches.sort_by_key(|branch| (branch.line_number, branch.column));
+    for branch in branches {
+        for outcome in &branch.outcomes {
+            let note = if outcome.hits.is_hit() {
+                format!("{} taken {}x", outcome.label, outcome.hits.count)
+            } else {
+                format!("{} never taken", outcome.label)
+            };
+            lines
+                .entry(branch.line_number)
+                .or_default()
+                .notes
+                .push((note, outcome.hits.is_hit()));
+        }
+    }
+
+    lines
+}
+
+fn write_summary(output: &mut String, summary: &CoverageSummary) {
+    writeln!(output, r#"<div class="summary">"#).unwrap();
+    for (name, count) in [
+        ("Statements", summary.statements),
+        ("Branches", summary.branches),
+        ("Functions", summary.functions),
+    ] {
+        writeln!(
+            output,
+            r#"<div><span class="{}">{:.2}%</span> {} <span class="count">{}/{}</span></div>"#,
+            get_level_class(count),
+            count.percent(),
+            name,
+            count.hit,
+            count.total
+        )
+        .unwrap();
+    }
+    writeln!(output, "</div>").unwrap();
+}
+
+fn write_count_cells(output: &mut String, count: CoverageCount) {
+    write!(
+        output,
+        r#"<td class="{}">{:.2}%</td><td>{}/{}</td>"#,
+        get_level_class(count),
+        count.percent(),
+        count.hit,
+        count.total
+    )
+    .unwrap();
+}
+
+/// Uses the same levels as the coverage table printed in the terminal
+fn get_level_class(count: CoverageCount) -> &'static str {
+    let percent = count.percent();
+    if percent >= 80.0 {
+        "high"
+    } else if percent >= 50.0 {
+        "medium"
+    } else {
+        "low"
+    }
+}
+
+fn generate_page(title: &str, body: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n{}</body>\n</html>\n",
+        escape_markup(title),
+        STYLE,
+        body
+    )
+}
+
+/// Sources can be nested in folders (e.g. `helpers/auth`), but the pages are all written next to the index page.
+/// Any other character than a letter, digit or `-` is written as `_` followed by its bytes in hex (e.g.
+/// `helpers_2Fauth`), so that no two sources get the same page.
+fn get_page_file_name(source_name: &str) -> String {
+    let mut name = String::new();
+    for c in source_name.chars() {
+        if c.is_ascii_alphanumeric() || c == '-' {
+            name.push(c);
+        } else {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                write!(name, "_{:02X}", byte).unwrap();
+            }
+        }
+    }
+    format!("{}.rhai.html", name)
+}
@@ -1,103 +1,1012 @@
 use super::test_coverage_container::TestCoverageContainer;
-use regex::Regex;
-use std::sync::{Arc, Mutex};
+use rhai::{
+    ASTFlags, ASTNode, Engine, Expr, FlowControl, OptimizationLevel, ParseError, Position, Stmt,
+    StmtBlock, SwitchCasesCollection, Token, AST,
+};
+use std::cmp::Reverse;
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
 
-/// This function is very experimental and could use a lot of cleaning up.
-/// Basically what is does is look for certain patterns via RegEx to find stuff like functions, statements, and blocks (if statements) and inject the appropriate instrumentation
-/// Additionally, it registers the statement/function/block against the test_coverage_container.
-/// We then calculate coverage by looking at the difference of registered functions/statements/blocks versus which ones actually had their tracking functions called
+/// Rhai compiles closures into functions named `anon$<hash>`.
+/// They aren't reported as functions, but the statements in their body are.
+const ANONYMOUS_FUNCTION_PREFIX: &str = "anon$";
+
+/// A line/column pair from a rhai [Position], which can be compared (unlike the position itself)
+type Location = (usize, usize);
+
+/// Some instrumentation code and where in the source it gets inserted
+struct Insertion {
+    offset: usize,
+    code: String,
+    /// Decides the order of insertions at the same offset, so that the code they wrap stays nested (see `apply()`)
+    order: (bool, Reverse<usize>),
+}
+
+/// Instruments a module's source code with coverage tracking functions.
 ///
-/// In an ideal world, we would probably iterate over the AST and inject in that way but the AST in Rhai has a lot of limitations... like it being immutable... and when walking the AST it doesn't walk function definitions
-/// To switch to an AST.walk() approach, we'd need a decent amount of changes upstream in Rhai itself.
-pub fn instrument_line(
-    i: usize,
-    line: &str,
+/// The source is parsed with rhai (with a token callback so we know where every token is), then the AST is walked
+/// to find every statement, function and branch (`if`, `switch`, `??` and `?.`). Their tracking functions are inserted
+/// at the positions the parser reported, so multi-line statements, closures and code inside strings or comments are
+/// handled the same way rhai itself sees them. Each statement/function/branch is also registered against the
+/// test_coverage_container, so coverage can be calculated by comparing what was registered with what had its tracking
+/// function called. Branches are registered with all of their outcomes, so we can tell which way a branch never went.
+///
+/// Fails with the parse error when the source doesn't parse, in which case nothing is registered.
+pub fn instrument_source(
+    source: &str,
     path: &str,
     test_coverage_container: Arc<Mutex<TestCoverageContainer>>,
-) -> String {
-    let mut result = String::from(line);
-
-    if let Some(captures) = Regex::new(r#"fn (.+?)\(.*?\)\s*?\{"#)
-        .unwrap()
-        .captures(line)
-    {
-        // Instrument Functions
-        if let Some(matched) = captures.get(1) {
-            let function_name = matched.as_str();
-
-            test_coverage_container.lock().unwrap().add_function(
-                function_name.to_string(),
-                path.to_string(),
-                (i as i64) + 1,
+) -> Result<String, ParseError> {
+    let (ast, tokens) = parse(source)?;
+
+    let mut instrumenter = Instrumenter {
+        source,
+        path,
+        tokens,
+        test_coverage_container: test_coverage_container.lock().unwrap(),
+        insertions: Vec::new(),
+        visited: HashSet::new(),
+    };
+
+    for stmt in ast.statements() {
+        stmt.walk(&mut Vec::new(), &mut |nodes| instrumenter.visit(nodes));
+    }
+
+    for function in ast.iter_fn_def() {
+        // Closures without braces are a single expression, which is covered along with the statement it's part of
+        let body_start = match instrumenter.block_start(&function.body) {
+            Some(body_start) => body_start,
+            None => continue,
+        };
+
+        if !function.name.starts_with(ANONYMOUS_FUNCTION_PREFIX) {
+            instrumenter.instrument_function(&function.name, function.params.len(), body_start);
+        }
+
+        for stmt in function.body.iter() {
+            stmt.walk(&mut Vec::new(), &mut |nodes| instrumenter.visit(nodes));
+        }
+    }
+
+    Ok(instrumenter.apply())
+}
+
+struct Instrumenter<'a> {
+    source: &'a str,
+    path: &'a str,
+    /// Every token the parser read, in order
+    tokens: Vec<(Token, Location)>,
+    test_coverage_container: MutexGuard<'a, TestCoverageContainer>,
+    insertions: Vec<Insertion>,
+    /// The address of every AST node visited so far
+    visited: HashSet<usize>,
+}
+
+impl Instrumenter<'_> {
+    /// Called for every node of the AST, instruments the statements and branches
+    fn visit(&mut self, nodes: &[ASTNode]) -> bool {
+        // A switch arm with several values (e.g. `1 | 2 => ...`) is walked once for each of them
+        let node_address = match nodes.last() {
+            Some(ASTNode::Stmt(stmt)) => *stmt as *const Stmt as usize,
+            Some(ASTNode::Expr(expr)) => *expr as *const Expr as usize,
+            _ => return true,
+        };
+        if !self.visited.insert(node_address) {
+            return true;
+        }
+
+        match nodes.last() {
+            Some(ASTNode::Stmt(stmt)) => self.visit_stmt(stmt, nodes),
+            Some(ASTNode::Expr(Expr::Coalesce(operands, position))) => {
+                self.instrument_coalesce(operands, *position);
+            }
+            Some(ASTNode::Expr(Expr::Dot(_, flags, position)))
+                if flags.contains(ASTFlags::NEGATED) =>
+            {
+                self.instrument_optional_chain(*position, nodes);
+            }
+            _ => {}
+        }
+
+        true
+    }
+
+    fn visit_stmt(&mut self, stmt: &Stmt, nodes: &[ASTNode]) {
+        // Blocks are covered through the statements inside of them, and the rest isn't code that was written
+        if matches!(stmt, Stmt::Block(..) | Stmt::Noop(..) | Stmt::Share(..)) {
+            return;
+        }
+
+        // Statements
+        if let Some(start) = self.statement_start(stmt, nodes) {
+            let (line, column) = start;
+            self.test_coverage_container.add_statement(
+                self.path.to_string(),
+                line as i64,
+                column as i64,
+            );
+            self.insert(
+                start,
+                format!(
+                    "rhai_test_coverage_instrument_statement({:?},{},{}); ",
+                    self.path, line, column
+                ),
+            );
+        }
+
+        // Branches
+        match stmt {
+            Stmt::If(flow_control, position) => {
+                self.instrument_if(flow_control, *position);
+            }
+            Stmt::Switch(switch, position) => {
+                self.instrument_switch(&switch.1, *position);
+            }
+            _ => {}
+        }
+    }
+
+    /// Registers a function and instruments the start of its body.
+    /// The function is registered at the line of its `fn` keyword.
+    fn instrument_function(&mut self, function_name: &str, arity: usize, body_start: Location) {
+        let line = self
+            .tokens
+            .iter()
+            .rev()
+            .skip_while(|(_, location)| *location >= body_start)
+            .find(|(token, _)| *token == Token::Fn)
+            .map(|(_, (line, _))| *line)
+            .unwrap_or(body_start.0);
+
+        self.test_coverage_container.add_function(
+            function_name.to_string(),
+            arity,
+            self.path.to_string(),
+            line as i64,
+        );
+        self.insert_after(
+            body_start,
+            format!(
+                " rhai_test_coverage_instrument_function({:?},{:?},{}); ",
+                function_name, self.path, line
+            ),
+        );
+    }
+
+    /// An `if` has two outcomes: its condition was true or false. The condition is wrapped so that both are
+    /// recorded, which also covers an `if` without an `else` (and each `if` of an `else if` has its own outcomes).
+    fn instrument_if(&mut self, flow_control: &FlowControl, position: Position) -> Option<()> {
+        let location = to_location(position)?;
+        let body_start = self.block_start(&flow_control.body)?;
+        let condition_start = self.tokens.get(self.token_index(location) + 1)?.1;
+
+        let call = self.add_branch(location, vec!["then".to_string(), "else".to_string()]);
+        self.wrap(
+            condition_start,
+            body_start,
+            format!("rhai_test_coverage_instrument_condition({}, ", call),
+            ")".to_string(),
+        );
+
+        Some(())
+    }
+
+    /// Every arm of a `switch` is an outcome, and so is not matching any arm when there's no `_` arm.
+    ///
+    /// The arms are found from the tokens inside the switch block: each `=>` at the top level of the block starts an
+    /// arm, which runs until the next `,` (or the end of its block). Expression arms are turned into blocks so their
+    /// tracking function can be called before the expression, and a `_` arm is added to track the implicit default.
+    fn instrument_switch(
+        &mut self,
+        cases: &SwitchCasesCollection,
+        position: Position,
+    ) -> Option<()> {
+        let location = to_location(position)?;
+        let block_open = self.skip_to(self.token_index(location) + 1, |token| {
+            *token == Token::LeftBrace
+        });
+        if self.tokens.get(block_open)?.0 != Token::LeftBrace {
+            return None;
+        }
+        let block_close = self.skip_to(block_open + 1, |_| false);
+
+        let mut labels = Vec::new();
+        let mut arms = Vec::new();
+        let mut needs_comma = false;
+        let mut pattern_start = block_open + 1;
+        while pattern_start < block_close {
+            let arrow = self.skip_to(pattern_start, |token| *token == Token::DoubleArrow);
+            if arrow >= block_close {
+                break;
+            }
+
+            let pattern = self.source_between(pattern_start, arrow);
+            labels.push(if pattern == "_" {
+                "default".to_string()
+            } else {
+                format!("case {}", pattern)
+            });
+
+            let arm_start = arrow + 1;
+            let is_block = self.tokens.get(arm_start)?.0 == Token::LeftBrace;
+            let arm_end = if is_block {
+                self.skip_to(arm_start + 1, |_| false)
+            } else {
+                self.skip_to(arm_start, |token| *token == Token::Comma)
+            };
+            arms.push((arm_start, arm_end, is_block));
+
+            // The next arm starts after the `,`, which is optional after a block
+            let has_comma =
+                self.tokens.get(arm_end + 1).map(|(token, _)| token) == Some(&Token::Comma);
+            needs_comma = !is_block && arm_end == block_close;
+            pattern_start = if is_block && has_comma {
+                arm_end + 2
+            } else {
+                arm_end + 1
+            };
+        }
+        if labels.is_empty() {
+            return None;
+        }
+        let implicit_default = labels.len();
+        if cases.def_case.is_none() {
+            labels.push("default".to_string());
+        }
+
+        let call = self.add_branch(location, labels);
+        for (outcome, (arm_start, arm_end, is_block)) in arms.into_iter().enumerate() {
+            let track = format!("rhai_test_coverage_instrument_branch({},{})", call, outcome);
+            if is_block {
+                self.insert_after(self.tokens[arm_start].1, format!(" {}; ", track));
+            } else {
+                self.wrap(
+                    self.tokens[arm_start].1,
+                    self.tokens[arm_end].1,
+                    format!("{{ {}; ", track),
+                    " }".to_string(),
+                );
+            }
+        }
+        if cases.def_case.is_none() {
+            self.insert(
+                self.tokens[block_close].1,
+                format!(
+                    "{}_ => rhai_test_coverage_instrument_branch({},{}) ",
+                    if needs_comma { ", " } else { "" },
+                    call,
+                    implicit_default
+                ),
             );
+        }
+
+        Some(())
+    }
+
+    /// Each operand of `a ?? b ?? c` is an outcome: the one whose value is used. Every operand but the last is
+    /// wrapped so its value can be checked, if it's `()` the next operand is evaluated (and used if it's the last).
+    fn instrument_coalesce(&mut self, operands: &[Expr], position: Position) -> Option<()> {
+        let location = to_location(position)?;
+        let labels = if operands.len() == 2 {
+            vec!["?? left".to_string(), "?? right".to_string()]
+        } else {
+            (1..=operands.len())
+                .map(|operand| format!("?? operand {}", operand))
+                .collect()
+        };
 
-            let instrumentation = format!(
-                "rhai_test_coverage_instrument_function(\"{}\",\"{}\",{} );",
-                function_name,
-                path,
-                (i as i64) + 1
+        let mut spans = Vec::new();
+        for pair in operands.windows(2) {
+            let next_start = self.token_index(expr_bounds(&pair[1], None)?.0);
+            let operator = (0..next_start)
+                .rev()
+                .find(|index| self.tokens[*index].0 == Token::DoubleQuestion)?;
+            let bounds = expr_bounds(&pair[0], None)?;
+            spans.push(self.span(bounds, operator)?);
+        }
+
+        let call = self.add_branch(location, labels);
+        for (outcome, (start, end)) in spans.into_iter().enumerate() {
+            self.wrap(
+                start,
+                end,
+                format!("rhai_test_coverage_instrument_value({},{}, ", call, outcome),
+                ")".to_string(),
             );
+        }
+
+        Some(())
+    }
+
+    /// `a?.b` has two outcomes: `a` had a value so `.b` was read, or `a` was `()` and the chain short-circuited.
+    /// Everything in front of the `?.` is wrapped so its value can be checked.
+    ///
+    /// Chains that are assigned to or call a method after the `?.` are left alone, as wrapping what's in front of the
+    /// `?.` would make them work on a copy of the value instead of the variable itself.
+    fn instrument_optional_chain(&mut self, position: Position, nodes: &[ASTNode]) -> Option<()> {
+        let location = to_location(position)?;
+        let operator = self.token_index(location);
+        if self.tokens.get(operator)?.0 != Token::Elvis {
+            return None;
+        }
+
+        // The whole chain this `?.` is part of, e.g. `a.b?.c.d` for the `?.` between `b` and `c`
+        let chain_index = (0..nodes.len())
+            .rev()
+            .take_while(|index| {
+                matches!(
+                    nodes[*index],
+                    ASTNode::Expr(Expr::Dot(..) | Expr::Index(..))
+                )
+            })
+            .last()?;
+        let chain = match nodes[chain_index] {
+            ASTNode::Expr(chain) => chain,
+            _ => return None,
+        };
+
+        if let Some(ASTNode::Stmt(Stmt::Assignment(assignment))) =
+            chain_index.checked_sub(1).map(|index| &nodes[index])
+        {
+            if std::ptr::eq(&assignment.1.lhs, chain) {
+                return None;
+            }
+        }
+        let mut has_method_call = false;
+        chain.walk(&mut Vec::new(), &mut |nodes: &[ASTNode]| {
+            if let Some(ASTNode::Expr(expr @ Expr::MethodCall(..))) = nodes.last() {
+                has_method_call |=
+                    to_location(expr.position()).is_some_and(|method| method > location);
+            }
+            true
+        });
+        if has_method_call {
+            return None;
+        }
+
+        let (start, end) = self.span(expr_bounds(chain, Some(location))?, operator)?;
+        let call = self.add_branch(
+            location,
+            vec!["?. continued".to_string(), "?. short-circuited".to_string()],
+        );
+        self.wrap(
+            start,
+            end,
+            format!("rhai_test_coverage_instrument_value({},0, ", call),
+            ")".to_string(),
+        );
+
+        Some(())
+    }
+
+    /// Registers a branch with its possible outcomes and returns the arguments its tracking functions are called with
+    fn add_branch(&mut self, (line, column): Location, outcomes: Vec<String>) -> String {
+        self.test_coverage_container.add_branch(
+            self.path.to_string(),
+            line as i64,
+            column as i64,
+            outcomes,
+        );
+        format!("{:?},{},{}", self.path, line, column)
+    }
+
+    /// Finds where a statement starts in the source.
+    ///
+    /// The position rhai gives a statement isn't always its first token (e.g. for `a + b` it's the `+`), so we take
+    /// the earliest position of anything in the statement and then move back over any tokens in front of it (like
+    /// `let`, `(` or `return`) until we reach the end of the previous statement or the start of the block.
+    ///
+    /// Returns None for anything that isn't a statement of its own, e.g. the `if` of an `else if` or an `if` expression.
+    fn statement_start(&self, stmt: &Stmt, nodes: &[ASTNode]) -> Option<Location> {
+        let mut earliest = to_location(stmt.position())?;
+        stmt.walk(&mut Vec::new(), &mut |nodes: &[ASTNode]| {
+            if let Some(location) = nodes
+                .last()
+                .and_then(|node| to_location(start_position(node)))
+            {
+                earliest = earliest.min(location);
+            }
+            true
+        });
+
+        let mut index = self.token_index(earliest);
+        while index > 0 {
+            match self.tokens[index - 1].0 {
+                Token::SemiColon | Token::LeftBrace | Token::RightBrace | Token::Comment(..) => {
+                    break
+                }
+                // There's nowhere to insert anything between `else` and `if`
+                Token::Else => return None,
+                _ => index -= 1,
+            }
+        }
+        let start = self.tokens.get(index)?.1;
+
+        // A statement inside an expression (e.g. `let x = if a { 1 } else { 2 };`) is part of the statement around
+        // it, unless it's inside one of the expression's blocks
+        if let Some(ASTNode::Expr(parent)) = nodes.len().checked_sub(2).map(|index| &nodes[index]) {
+            if let Some(parent_start) = to_location(parent.position()) {
+                if start < parent_start {
+                    return None;
+                }
+            }
+        }
+
+        Some(start)
+    }
+
+    /// Where the `{` of a block is, or None if the block isn't wrapped in braces (or doesn't exist)
+    fn block_start(&self, block: &StmtBlock) -> Option<Location> {
+        let start = to_location(block.position())?;
+        self.tokens
+            .iter()
+            .any(|(token, location)| *location == start && *token == Token::LeftBrace)
+            .then_some(start)
+    }
+
+    /// The index of the token at (or else the first one after) the given location
+    fn token_index(&self, location: Location) -> usize {
+        self.tokens
+            .partition_point(|(_, token_location)| *token_location < location)
+    }
+
+    /// Moves forward from the token at `index` (skipping over anything in brackets) to the first token that `stop`
+    /// returns true for, or to the closing bracket of whatever the tokens are inside of
+    fn skip_to(&self, mut index: usize, stop: impl Fn(&Token) -> bool) -> usize {
+        let mut depth = 0;
+        while let Some((token, _)) = self.tokens.get(index) {
+            if depth == 0 && stop(token) {
+                break;
+            }
+            if is_opening_bracket(token) {
+                depth += 1;
+            } else if is_closing_bracket(token) {
+                if depth == 0 {
+                    break;
+                }
+                depth -= 1;
+            }
+            index += 1;
+        }
+        index
+    }
+
+    /// The source of an expression that runs up to the token at `end`, as locations that can be wrapped.
+    ///
+    /// The expression's brackets aren't part of the AST, so they're matched up from the tokens: a closing bracket
+    /// inside the expression moves the start back to its opening bracket (e.g. `(a + b) * c`), while one after it
+    /// ends the expression early (e.g. the `)` of `(x ?? y) ?? z`, whose operands are flattened into one list).
+    fn span(
+        &self,
+        (first, last): (Location, Location),
+        end: usize,
+    ) -> Option<(Location, Location)> {
+        let mut start = self.token_index(first);
+        let mut end = end;
+        let mut index = start;
+        let mut depth = 0;
+        while index < end {
+            let (token, location) = &self.tokens[index];
+            if is_opening_bracket(token) {
+                depth += 1;
+            } else if is_closing_bracket(token) {
+                if depth > 0 {
+                    depth -= 1;
+                } else if *location > last {
+                    end = index;
+                    break;
+                } else {
+                    start = (0..start).rev().find(|opening| {
+                        self.skip_to(opening + 1, |_| false) == index
+                            && is_opening_bracket(&self.tokens[*opening].0)
+                    })?;
+                }
+            }
+            index += 1;
+        }
+
+        Some((self.tokens.get(start)?.1, self.tokens.get(end)?.1))
+    }
+
+    /// The source code from the token at `start` up to the token at `end`, on a single line
+    fn source_between(&self, start: usize, end: usize) -> String {
+        let start = to_offset(self.source, self.tokens[start].1);
+        let end = to_offset(self.source, self.tokens[end].1);
+        self.source[start..end]
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Inserts code in front of the token at the given location
+    fn insert(&mut self, location: Location, code: String) {
+        let offset = to_offset(self.source, location);
+        self.insertions.push(Insertion {
+            offset,
+            code,
+            order: (true, Reverse(usize::MAX)),
+        });
+    }
+
+    /// Inserts code right after the (single character) token at the given location, e.g. a `{`
+    fn insert_after(&mut self, location: Location, code: String) {
+        let offset = to_offset(self.source, location) + 1;
+        self.insertions.push(Insertion {
+            offset,
+            code,
+            order: (true, Reverse(usize::MAX)),
+        });
+    }
+
+    /// Wraps the source from the token at `start` up to (but not including) the token at `end`
+    fn wrap(&mut self, start: Location, end: Location, open: String, close: String) {
+        let start = to_offset(self.source, start);
+        let end = to_offset(self.source, end);
+        self.insertions.push(Insertion {
+            offset: start,
+            code: open,
+            order: (true, Reverse(end)),
+        });
+        self.insertions.push(Insertion {
+            offset: end,
+            code: close,
+            order: (false, Reverse(start)),
+        });
+    }
 
-            result = Regex::new(r#"(?P<c1>fn .+?\(.*?\)\s*?\{)(?P<c2>.*?)"#)
+    /// Returns the source with all the instrumentation inserted.
+    ///
+    /// At the same offset, code that closes a wrap comes first (the innermost one first), then code that opens one
+    /// (the outermost one first). Plain insertions count as the outermost, and otherwise keep the order they were
+    /// added in.
+    fn apply(mut self) -> String {
+        self.insertions
+            .sort_by_key(|insertion| (insertion.offset, insertion.order));
+
+        let mut result = String::with_capacity(self.source.len() + self.insertions.len() * 64);
+        let mut copied = 0;
+        for insertion in &self.insertions {
+            result.push_str(&self.source[copied..insertion.offset]);
+            result.push_str(&insertion.code);
+            copied = insertion.offset;
+        }
+        result.push_str(&self.source[copied..]);
+
+        result
+    }
+}
+
+/// The earliest and latest positions of anything in an expression, only counting what comes before `before`
+fn expr_bounds(expr: &Expr, before: Option<Location>) -> Option<(Location, Location)> {
+    let mut bounds: Option<(Location, Location)> = None;
+    expr.walk(&mut Vec::new(), &mut |nodes: &[ASTNode]| {
+        if let Some(node) = nodes.last() {
+            for location in [
+                to_location(start_position(node)),
+                to_location(node.position()),
+            ]
+            .into_iter()
+            .flatten()
+            .filter(|location| before.is_none_or(|before| *location < before))
+            {
+                bounds = Some(match bounds {
+                    Some((first, last)) => (first.min(location), last.max(location)),
+                    None => (location, location),
+                });
+            }
+        }
+        true
+    });
+    bounds
+}
+
+fn start_position(node: &ASTNode) -> Position {
+    match node {
+        ASTNode::Expr(expr) => expr.start_position(),
+        node => node.position(),
+    }
+}
+
+fn is_opening_bracket(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::LeftParen
+            | Token::LeftBracket
+            | Token::LeftBrace
+            | Token::MapStart
+            | Token::QuestionBracket
+    )
+}
+
+fn is_closing_bracket(token: &Token) -> bool {
+    matches!(
+        token,
+        Token::RightParen | Token::RightBracket | Token::RightBrace
+    )
+}
+
+/// Parses the source without optimizations (so no code gets removed), keeping hold of every token the parser read
+#[allow(deprecated)]
+fn parse(source: &str) -> Result<(AST, Vec<(Token, Location)>), ParseError> {
+    let tokens = Arc::new(Mutex::new(Vec::<(Token, Location)>::new()));
+    let cloned_tokens = tokens.clone();
+
+    let mut engine = Engine::new_raw();
+    engine.set_optimization_level(OptimizationLevel::None);
+    engine.on_parse_token(move |token, position, _| {
+        if let Some(location) = to_location(position) {
+            cloned_tokens
+                .lock()
                 .unwrap()
-                .replace(&result, format!("$c1 {} $c2", instrumentation))
-                .to_string();
+                .push((token.clone(), location));
+        }
+        token
+    });
+
+    let ast = engine.compile(source)?;
+    let mut tokens = tokens.lock().unwrap().clone();
+    tokens.sort_by_key(|(_, location)| *location);
+
+    Ok((ast, tokens))
+}
+
+fn to_location(position: Position) -> Option<Location> {
+    Some((position.line()?, position.position()?))
+}
+
+/// Converts a 1-based line and column (counted in characters) into a byte offset in the source
+fn to_offset(source: &str, (line, column): Location) -> usize {
+    let line_offset = source
+        .split_inclusive('\n')
+        .take(line.saturating_sub(1))
+        .map(|line| line.len())
+        .sum::<usize>();
+    let line_text = &source[line_offset..];
+    let column_offset = line_text
+        .char_indices()
+        .nth(column.saturating_sub(1))
+        .map(|(offset, _)| offset)
+        .unwrap_or(line_text.len());
+
+    line_offset + column_offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::extensions::file_coverage;
+    use rhai::Dynamic;
+
+    const PATH: &str = "lib";
+
+    /// Instruments a source and checks that the result still compiles
+    fn instrument(source: &str) -> (String, Arc<Mutex<TestCoverageContainer>>) {
+        let test_coverage_container = Arc::new(Mutex::new(TestCoverageContainer::new()));
+        let instrumented = instrument_source(source, PATH, test_coverage_container.clone())
+            .expect("the source should parse");
+
+        if let Err(error) = Engine::new().compile(&instrumented) {
+            panic!(
+                "the instrumented source doesn't compile: {}\n{}",
+                error, instrumented
+            );
         }
-    } else if Regex::new(r#".+?\(.*?\);"#).unwrap().is_match(line) {
-        // Function Call Statements
-        test_coverage_container
-            .lock()
-            .unwrap()
-            .add_statement(path.to_string(), (i as i64) + 1);
 
-        let instrumentation = format!(
-            "rhai_test_coverage_instrument_statement(\"{}\",{} );",
-            path,
-            (i as i64) + 1
+        (instrumented, test_coverage_container)
+    }
+
+    /// Runs a source as is and instrumented, and returns both results (as strings, as values can't be compared)
+    fn evaluate(
+        source: &str,
+        instrumented: &str,
+        test_coverage_container: &Arc<Mutex<TestCoverageContainer>>,
+    ) -> (String, String) {
+        let expected = Engine::new().eval::<Dynamic>(source).unwrap();
+
+        let mut engine = Engine::new();
+        file_coverage::register_rhai_functions_and_types(
+            &mut engine,
+            test_coverage_container.clone(),
         );
-        result = format!("{} {}", line, instrumentation);
-    } else if Regex::new(r#"(let )?.+?=.+?;"#).unwrap().is_match(line) {
-        // Variable declarations
-        test_coverage_container
-            .lock()
-            .unwrap()
-            .add_statement(path.to_string(), (i as i64) + 1);
-
-        let instrumentation = format!(
-            "rhai_test_coverage_instrument_statement(\"{}\",{} );",
-            path,
-            (i as i64) + 1
-        );
-        result = format!("{} {}", line, instrumentation);
-    } else if Regex::new(r#"(else|else if|if).+?\{"#)
-        .unwrap()
-        .is_match(line)
-    {
-        test_coverage_container
-            .lock()
-            .unwrap()
-            .add_branch(path.to_string(), (i as i64) + 1);
+        let actual = engine.eval::<Dynamic>(instrumented).unwrap();
 
-        // Branches
-        let instrumentation = format!(
-            "rhai_test_coverage_instrument_branch(\"{}\",{} );",
-            path,
-            (i as i64) + 1
-        );
-        result = format!("{} {}", line, instrumentation);
-    } else if Regex::new(r#"throw.+?\{"#).unwrap().is_match(line) {
-        // throws
-        test_coverage_container
-            .lock()
-            .unwrap()
-            .add_statement(path.to_string(), (i as i64) + 1);
+        (expected.to_string(), actual.to_string())
+    }
+
+    /// The line and column of every registered statement, in order
+    fn statements(test_coverage_container: &Arc<Mutex<TestCoverageContainer>>) -> Vec<(i64, i64)> {
+        let container = test_coverage_container.lock().unwrap();
+        let mut statements = container
+            .get_sorted_sources()
+            .iter()
+            .flat_map(|source| source.statements.values())
+            .map(|statement| (statement.line_number, statement.column))
+            .collect::<Vec<_>>();
+        statements.sort();
+        statements
+    }
+
+    /// The name, arity, line and hit count of every registered function, in order of their lines
+    fn functions(
+        test_coverage_container: &Arc<Mutex<TestCoverageContainer>>,
+    ) -> Vec<(String, usize, i64, u64)> {
+        let container = test_coverage_container.lock().unwrap();
+        let mut functions = container
+            .get_sorted_sources()
+            .iter()
+            .flat_map(|source| source.functions.values())
+            .map(|function| {
+                (
+                    function.function_name.clone(),
+                    function.arity,
+                    function.line_number,
+                    function.hits.count,
+                )
+            })
+            .collect::<Vec<_>>();
+        functions.sort_by_key(|(_, _, line_number, _)| *line_number);
+        functions
+    }
+
+    /// Every registered branch with how many times each of its outcomes was taken, in order of their lines,
+    /// e.g. `2: then x1, else x0`
+    fn branches(test_coverage_container: &Arc<Mutex<TestCoverageContainer>>) -> Vec<String> {
+        let container = test_coverage_container.lock().unwrap();
+        let mut branches = container
+            .get_sorted_sources()
+            .iter()
+            .flat_map(|source| source.branches.values())
+            .collect::<Vec<_>>();
+        branches.sort_by_key(|branch| (branch.line_number, branch.column));
+        branches
+            .iter()
+            .map(|branch| {
+                let outcomes = branch
+                    .outcomes
+                    .iter()
+                    .map(|outcome| format!("{} x{}", outcome.label, outcome.hits.count))
+                    .collect::<Vec<_>>();
+                format!("{}: {}", branch.line_number, outcomes.join(", "))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn instruments_multi_line_statements_once() {
+        let (instrumented, test_coverage_container) = instrument(
+            "let x = 1 +
+    2;
+let map = #{
+    a: 1,
+    b: x
+};
+map.b",
+        );
 
-        let instrumentation = format!(
-            "rhai_test_coverage_instrument_statement(\"{}\",{} );",
-            path,
-            (i as i64) + 1
+        assert_eq!(
+            statements(&test_coverage_container),
+            vec![(1, 1), (3, 1), (7, 1)]
         );
-        result = format!("{} {}", instrumentation, line);
+        assert!(instrumented.contains("let x = 1 +\n    2;"));
+    }
+
+    #[test]
+    fn ignores_semicolons_and_braces_in_strings_and_comments() {
+        let (instrumented, test_coverage_container) = instrument(
+            r#"let a = "not; a { statement }";
+// let b = 1; {
+/* if c { d; } */
+let c = `also; not {`;
+a + c"#,
+        );
+
+        assert_eq!(
+            statements(&test_coverage_container),
+            vec![(1, 1), (4, 1), (5, 1)]
+        );
+        assert!(instrumented.contains(r#"let a = "not; a { statement }";"#));
+        assert!(instrumented.contains("// let b = 1; {\n/* if c { d; } */\n"));
+        assert!(instrumented.contains("let c = `also; not {`;"));
+    }
+
+    #[test]
+    fn instruments_functions_and_the_closures_inside_them() {
+        let source = "fn add(a, b) {
+    let sum = a + b;
+    sum
+}
+
+fn apply(value) {
+    let double = |x| {
+        let result = x * 2;
+        result
+    };
+    let increment = |x| x + 1;
+    increment.call(double.call(value))
+}
+
+let outer = || {
+    let inner = || {
+        add(1, 2)
+    };
+    inner.call()
+};
+outer.call() + apply(3)";
+        let (instrumented, test_coverage_container) = instrument(source);
+
+        let (expected, actual) = evaluate(source, &instrumented, &test_coverage_container);
+        assert_eq!(actual, expected);
+
+        // Closures aren't functions of their own, but the statements in their body are covered
+        assert_eq!(
+            functions(&test_coverage_container),
+            vec![("add".to_string(), 2, 1, 1), ("apply".to_string(), 1, 6, 1)]
+        );
+        assert_eq!(
+            statements(&test_coverage_container),
+            vec![
+                (2, 5),
+                (3, 5),
+                (7, 5),
+                (8, 9),
+                (9, 9),
+                (11, 5),
+                (12, 5),
+                (15, 1),
+                (16, 5),
+                (17, 9),
+                (19, 5),
+                (21, 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn instruments_switch_statements() {
+        let (_, test_coverage_container) = instrument(
+            "let x = 2;
+let result = switch x {
+    1 => \"one\",
+    2 | 3 => {
+        let y = x * 2;
+        y
+    }
+    _ => ()
+};
+switch x { 1 => print(x), 2 => print(x) }",
+        );
+
+        assert_eq!(
+            statements(&test_coverage_container),
+            vec![(1, 1), (2, 1), (5, 9), (6, 9), (10, 1)]
+        );
+    }
+
+    #[test]
+    fn tracks_both_outcomes_of_every_if() {
+        let source = "fn size(x) {
+    if x > 10 {
+        \"big\"
+    } else if x > 0 {
+        \"small\"
+    } else {
+        \"none\"
+    }
+}
+let total = 0;
+if total > 1 { total = 2; }
+[size(20), size(5), size(5), total]";
+        let (instrumented, test_coverage_container) = instrument(source);
+
+        let (expected, actual) = evaluate(source, &instrumented, &test_coverage_container);
+
+        assert_eq!(actual, expected);
+        assert_eq!(
+            branches(&test_coverage_container),
+            vec![
+                "2: then x1, else x2",
+                "4: then x2, else x0",
+                "11: then x0, else x1"
+            ]
+        );
+    }
+
+    #[test]
+    fn tracks_every_switch_arm_and_the_implicit_default() {
+        let source = "fn name(x) {
+    switch x {
+        1 => \"one\",
+        2 | 3 => { \"few\" }
+        4 => \"four\"
+    }
+}
+fn name_or_other(x) {
+    switch x { 1 => \"one\", _ => \"other\" }
+}
+let unmatched = switch 9 { 1 => \"one\" };
+[name(1), name(3), name(5), name_or_other(1), name_or_other(2), unmatched]";
+        let (instrumented, test_coverage_container) = instrument(source);
+
+        let (expected, actual) = evaluate(source, &instrumented, &test_coverage_container);
+
+        // The default added for a switch without a `_` arm returns () like the switch itself would
+        assert_eq!(actual, expected);
+        assert_eq!(
+            branches(&test_coverage_container),
+            vec![
+                "2: case 1 x1, case 2 | 3 x1, case 4 x0, default x1",
+                "9: case 1 x1, default x1",
+                "11: case 1 x0, default x1"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_coalesce_short_circuiting() {
+        let source = "let calls = [];
+let fallback = |value| { calls.push(value); value };
+let none = ();
+let a = 1 ?? fallback.call(2);
+let b = none ?? fallback.call(3);
+let c = none ?? none ?? fallback.call(4);
+let d = (none ?? 5) ?? fallback.call(6);
+[a, b, c, d, calls]";
+        let (instrumented, test_coverage_container) = instrument(source);
+
+        let (expected, actual) = evaluate(source, &instrumented, &test_coverage_container);
+
+        // Only the operands that were needed ran, so the fallback was called with 3 and 4
+        assert_eq!(actual, expected);
+        assert_eq!(actual, "[1, 3, 4, 5, [3, 4]]");
+        assert_eq!(
+            branches(&test_coverage_container),
+            vec![
+                "4: ?? left x1, ?? right x0",
+                "5: ?? left x0, ?? right x1",
+                "6: ?? operand 1 x0, ?? operand 2 x0, ?? operand 3 x1",
+                "7: ?? operand 1 x0, ?? operand 2 x1, ?? operand 3 x0"
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_optional_chains_short_circuiting() {
+        let source = "let calls = [];
+let get = |value| { calls.push(value); value };
+let user = #{ address: #{ city: \"Paris\" } };
+let none = ();
+let city = get.call(user)?.address.city;
+let missing = get.call(none)?.address.city;
+let nested = user.address?.city;
+[city, missing, nested, calls.len()]";
+        let (instrumented, test_coverage_container) = instrument(source);
+
+        let (expected, actual) = evaluate(source, &instrumented, &test_coverage_container);
+
+        // What's in front of each `?.` ran once, and nothing after it ran when it was ()
+        assert_eq!(actual, expected);
+        assert_eq!(actual, "[\"Paris\", (), \"Paris\", 2]");
+        assert_eq!(
+            branches(&test_coverage_container),
+            vec![
+                "5: ?. continued x1, ?. short-circuited x0",
+                "6: ?. continued x0, ?. short-circuited x1",
+                "7: ?. continued x1, ?. short-circuited x0"
+            ]
+        );
+    }
+
+    #[test]
+    fn fails_on_sources_that_do_not_parse() {
+        let test_coverage_container = Arc::new(Mutex::new(TestCoverageContainer::new()));
+
+        let result = instrument_source("let x = ;", PATH, test_coverage_container.clone());
+
+        assert!(result.is_err());
+        assert!(test_coverage_container
+            .lock()
+            .unwrap()
+            .get_sorted_sources()
+            .is_empty());
     }
-    result
 }
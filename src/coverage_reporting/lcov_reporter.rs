@@ -0,0 +1,92 @@
+use super::test_coverage_container::TestCoverageContainer;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+/// Generates an lcov.info tracefile (as read by Codecov, SonarQube, genhtml, etc.) from the collected coverage
+pub fn generate_report(test_coverage_container: &TestCoverageContainer) -> String {
+    let mut report = String::new();
+
+    for source in test_coverage_container.get_sorted_sources() {
+        writeln!(report, "TN:").unwrap();
+        writeln!(report, "SF:{}", source.file_path).unwrap();
+
+        // Functions (named with their arity, as Rhai functions with the same name but different parameters are distinct)
+        let mut functions = source.functions.values().collect::<Vec<_>>();
+        functions.sort_by_key(|function| function.line_number);
+        for function in &functions {
+            writeln!(
+                report,
+                "FN:{},{}/{}",
+                function.line_number, function.function_name, function.arity
+            )
+            .unwrap();
+        }
+        for function in &functions {
+            writeln!(
+                report,
+                "FNDA:{},{}/{}",
+                function.hits.count, function.function_name, function.arity
+            )
+            .unwrap();
+        }
+        writeln!(report, "FNF:{}", functions.len()).unwrap();
+        writeln!(
+            report,
+            "FNH:{}",
+            functions
+                .iter()
+                .filter(|function| function.hits.is_hit())
+                .count()
+        )
+        .unwrap();
+
+        // Branches (each branch is a block, and each of its outcomes one of the block's branches).
+        // An outcome of a branch that never ran at all is reported as `-` rather than 0.
+        let mut branches = source.branches.values().collect::<Vec<_>>();
+        branches.sort_by_key(|branch| (branch.line_number, branch.column));
+        let mut branch_count = 0;
+        let mut branch_hit_count = 0;
+        for (block, branch) in branches.iter().enumerate() {
+            let is_branch_hit = branch.outcomes.iter().any(|outcome| outcome.hits.is_hit());
+            for (index, outcome) in branch.outcomes.iter().enumerate() {
+                let taken = if is_branch_hit {
+                    outcome.hits.count.to_string()
+                } else {
+                    "-".to_string()
+                };
+                writeln!(
+                    report,
+                    "BRDA:{},{},{},{}",
+                    branch.line_number, block, index, taken
+                )
+                .unwrap();
+
+                branch_count += 1;
+                branch_hit_count += outcome.hits.is_hit() as usize;
+            }
+        }
+        writeln!(report, "BRF:{}", branch_count).unwrap();
+        writeln!(report, "BRH:{}", branch_hit_count).unwrap();
+
+        // Lines (a line can hold several statements, it was executed as many times as its most executed statement)
+        let mut lines = BTreeMap::<i64, u64>::new();
+        for statement in source.statements.values() {
+            let count = lines.entry(statement.line_number).or_default();
+            *count = (*count).max(statement.hits.count);
+        }
+        for (line_number, count) in &lines {
+            writeln!(report, "DA:{},{}", line_number, count).unwrap();
+        }
+        writeln!(report, "LF:{}", lines.len()).unwrap();
+        writeln!(
+            report,
+            "LH:{}",
+            lines.values().filter(|count| **count > 0).count()
+        )
+        .unwrap();
+
+        writeln!(report, "end_of_record").unwrap();
+    }
+
+    report
+}
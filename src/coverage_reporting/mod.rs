@@ -1,3 +1,94 @@
+pub(crate) mod cobertura_reporter;
+pub(crate) mod coverage_collection;
+pub(crate) mod coverage_threshold;
 pub(crate) mod file_coverage_module_resolver;
+pub(crate) mod html_reporter;
 pub(crate) mod instrumentation;
+pub(crate) mod lcov_reporter;
+pub(crate) mod test_attribution;
 pub(crate) mod test_coverage_container;
+
+use serde::Deserialize;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+use test_coverage_container::TestCoverageContainer;
+
+/// Where file based coverage reports get written when no coverage directory was configured
+pub const DEFAULT_COVERAGE_DIRECTORY: &str = "coverage";
+
+/// The different formats coverage can be reported in
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CoverageReporter {
+    /// The coverage table printed to the terminal
+    Text,
+    /// An lcov.info tracefile for Codecov, SonarQube, etc.
+    Lcov,
+    /// A static site with the annotated source of every file, in an html folder
+    Html,
+    /// A cobertura-coverage.xml file for Azure DevOps, GitLab, etc.
+    Cobertura,
+}
+
+/// Writes the coverage with every file based reporter into the coverage directory.
+/// Returns the paths of the files that were written.
+pub fn write_coverage_results(
+    reporters: &[CoverageReporter],
+    coverage_directory: Option<String>,
+    test_coverage_container: &TestCoverageContainer,
+) -> Result<Vec<String>, String> {
+    let coverage_directory = coverage_directory.unwrap_or(DEFAULT_COVERAGE_DIRECTORY.to_string());
+    let mut written_files = Vec::new();
+
+    for reporter in reporters {
+        // Each reporter writes one or more files, the first of which is the one to open
+        let (directory, files) = match reporter {
+            // Printed to the terminal rather than written to a file
+            CoverageReporter::Text => continue,
+            CoverageReporter::Lcov => (
+                PathBuf::from(&coverage_directory),
+                vec![(
+                    "lcov.info".to_string(),
+                    lcov_reporter::generate_report(test_coverage_container),
+                )],
+            ),
+            CoverageReporter::Cobertura => (
+                PathBuf::from(&coverage_directory),
+                vec![(
+                    "cobertura-coverage.xml".to_string(),
+                    cobertura_reporter::generate_report(test_coverage_container),
+                )],
+            ),
+            CoverageReporter::Html => (
+                Path::new(&coverage_directory).join("html"),
+                html_reporter::generate_report(test_coverage_container),
+            ),
+        };
+
+        fs::create_dir_all(&directory).map_err(|error| {
+            format!(
+                "Unable to create coverage directory {}. Error: {}",
+                directory.display(),
+                error
+            )
+        })?;
+
+        for (index, (file_name, report)) in files.into_iter().enumerate() {
+            let output_file = directory.join(file_name).display().to_string();
+            fs::write(&output_file, report).map_err(|error| {
+                format!(
+                    "Unable to write coverage report to {}. Error: {}",
+                    output_file, error
+                )
+            })?;
+
+            if index == 0 {
+                written_files.push(output_file);
+            }
+        }
+    }
+
+    Ok(written_files)
+}
@@ -0,0 +1,138 @@
+use super::test_coverage_container::{TestCoverageContainer, TestCoverageSource};
+use colored::*;
+use std::collections::BTreeSet;
+use std::fs;
+use tabled::{settings::Style, Table, Tabled};
+
+/// A line of a covered script, as passed to `--tests-covering <file>:<line>`
+#[derive(Debug, Clone)]
+pub struct CoveredLine {
+    pub file: String,
+    pub line_number: i64,
+}
+
+impl CoveredLine {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || {
+            format!(
+                "--tests-covering '{}' should be a file and a line number, e.g. router.rhai:12",
+                value
+            )
+        };
+
+        let (file, line_number) = value.rsplit_once(':').ok_or_else(invalid)?;
+        let line_number = line_number.parse::<i64>().map_err(|_| invalid())?;
+        if file.is_empty() || line_number < 1 {
+            return Err(invalid());
+        }
+
+        Ok(Self {
+            file: file.to_string(),
+            line_number,
+        })
+    }
+}
+
+#[derive(Tabled)]
+struct TestCoverageReportLine {
+    #[tabled(rename = "Test")]
+    test: String,
+
+    #[tabled(rename = "Source")]
+    source: String,
+
+    #[tabled(rename = "Covered Line #s")]
+    covered_lines: String,
+}
+
+/// Prints a table with the lines of every source that each test executed
+pub fn print_coverage_by_test(test_coverage_container: &TestCoverageContainer) {
+    let mut report_data = Vec::<TestCoverageReportLine>::new();
+
+    for source in test_coverage_container.get_sorted_sources() {
+        for (test, lines) in source.get_lines_by_test() {
+            report_data.push(TestCoverageReportLine {
+                test: test.to_string(),
+                source: source.name.clone(),
+                covered_lines: format_line_ranges(&lines),
+            });
+        }
+    }
+
+    // Grouped by test, with its sources in order
+    report_data.sort_by(|a, b| (&a.test, &a.source).cmp(&(&b.test, &b.source)));
+
+    if report_data.is_empty() {
+        println!("\n{}", "No test covered any line".yellow());
+        return;
+    }
+
+    let table = Table::new(report_data).with(Style::modern()).to_string();
+    println!("\n{}", table);
+}
+
+/// Prints the tests that executed a line of a covered script.
+/// Fails when none of the covered scripts is the file that was asked for.
+pub fn print_tests_covering(
+    test_coverage_container: &TestCoverageContainer,
+    covered_line: &CoveredLine,
+) -> Result<(), String> {
+    let source = test_coverage_container
+        .get_sorted_sources()
+        .into_iter()
+        .find(|source| is_matching_source(source, &covered_line.file))
+        .ok_or_else(|| {
+            format!(
+                "--tests-covering found no covered script matching {}",
+                covered_line.file
+            )
+        })?;
+
+    let tests = source.get_tests_covering_line(covered_line.line_number);
+    let line = format!("{}:{}", source.name, covered_line.line_number);
+    if tests.is_empty() {
+        println!("\n{}", format!("No tests cover {}", line).yellow());
+        return Ok(());
+    }
+
+    println!("\nTests covering {}:", line);
+    tests.iter().for_each(|test| println!("  {}", test));
+
+    Ok(())
+}
+
+/// The file can be given as the source name (e.g. `helpers/auth`), with its extension, or as a path to the script
+fn is_matching_source(source: &TestCoverageSource, file: &str) -> bool {
+    let file = file.trim_start_matches("./");
+    if file == source.name || file.strip_suffix(".rhai") == Some(source.name.as_str()) {
+        return true;
+    }
+
+    match (fs::canonicalize(file), fs::canonicalize(&source.file_path)) {
+        (Ok(file), Ok(file_path)) => file == file_path,
+        _ => false,
+    }
+}
+
+/// Collapses consecutive line numbers into ranges, e.g. `1-3,7,9-10`
+fn format_line_ranges(lines: &BTreeSet<i64>) -> String {
+    let mut ranges = Vec::<(i64, i64)>::new();
+    for line in lines {
+        match ranges.last_mut() {
+            Some((_, end)) if *end + 1 == *line => *end = *line,
+            _ => ranges.push((*line, *line)),
+        }
+    }
+
+    ranges
+        .iter()
+        .map(|(start, end)| {
+            if start == end {
+                start.to_string()
+            } else {
+                format!("{}-{}", start, end)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -1,39 +1,193 @@
+use crate::engine::test_container::GROUP_SEPARATOR;
 use colored::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
 use tabled::{settings::Style, Table, Tabled};
 
 #[derive(Debug)]
-struct TestCoverageSource {
+pub struct TestCoverageSource {
     pub name: String,
-    statements: HashMap<String, StatementCoverage>,
-    branches: HashMap<String, BranchCoverage>,
-    functions: HashMap<String, FunctionCoverage>,
+    /// The file the source was loaded from (the same as its name until the module resolver sets it)
+    pub file_path: String,
+    pub statements: HashMap<String, StatementCoverage>,
+    pub branches: HashMap<String, BranchCoverage>,
+    pub functions: HashMap<String, FunctionCoverage>,
 }
 
-#[derive(Debug)]
-struct FunctionCoverage {
-    #[allow(dead_code)]
+impl TestCoverageSource {
+    /// How many of the source's statements, branches and functions were hit
+    pub fn summary(&self) -> CoverageSummary {
+        CoverageSummary {
+            statements: CoverageCount {
+                hit: self.statements.values().filter(|s| s.hits.is_hit()).count(),
+                total: self.statements.len(),
+            },
+            branches: CoverageCount {
+                hit: self
+                    .branches
+                    .values()
+                    .flat_map(|b| &b.outcomes)
+                    .filter(|o| o.hits.is_hit())
+                    .count(),
+                total: self.branches.values().map(|b| b.outcomes.len()).sum(),
+            },
+            functions: CoverageCount {
+                hit: self.functions.values().filter(|f| f.hits.is_hit()).count(),
+                total: self.functions.len(),
+            },
+        }
+    }
+
+    /// The tests that executed anything on a line (a statement, the start of a function or a branch outcome)
+    pub fn get_tests_covering_line(&self, line_number: i64) -> BTreeSet<&CoveringTest> {
+        let statements = self
+            .statements
+            .values()
+            .filter(|statement| statement.line_number == line_number)
+            .map(|statement| &statement.hits);
+        let functions = self
+            .functions
+            .values()
+            .filter(|function| function.line_number == line_number)
+            .map(|function| &function.hits);
+        let branches = self
+            .branches
+            .values()
+            .filter(|branch| branch.line_number == line_number)
+            .flat_map(|branch| branch.outcomes.iter().map(|outcome| &outcome.hits));
+
+        statements
+            .chain(functions)
+            .chain(branches)
+            .flat_map(|hits| &hits.tests)
+            .collect()
+    }
+
+    /// The lines each test executed a statement on
+    pub fn get_lines_by_test(&self) -> BTreeMap<&CoveringTest, BTreeSet<i64>> {
+        let mut lines = BTreeMap::<&CoveringTest, BTreeSet<i64>>::new();
+        for statement in self.statements.values() {
+            for test in &statement.hits.tests {
+                lines.entry(test).or_default().insert(statement.line_number);
+            }
+        }
+        lines
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CoverageCount {
+    pub hit: usize,
+    pub total: usize,
+}
+
+impl CoverageCount {
+    /// Nothing to cover counts as fully covered
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            100.0
+        } else {
+            (self.hit as f64 / self.total as f64) * 100.0
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CoverageSummary {
+    pub statements: CoverageCount,
+    pub branches: CoverageCount,
+    pub functions: CoverageCount,
+}
+
+impl CoverageSummary {
+    /// Adds the counts of another summary to this one
+    pub fn add(&mut self, other: &CoverageSummary) {
+        self.statements.hit += other.statements.hit;
+        self.statements.total += other.statements.total;
+        self.branches.hit += other.branches.hit;
+        self.branches.total += other.branches.total;
+        self.functions.hit += other.functions.hit;
+        self.functions.total += other.functions.total;
+    }
+}
+
+/// A test that executed some code, identified by its file and full name (including any describe() groups)
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct CoveringTest {
+    pub file_path: String,
+    pub name: String,
+}
+
+impl fmt::Display for CoveringTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}{}", self.file_path, GROUP_SEPARATOR, self.name)
+    }
+}
+
+/// How many times a function, statement or branch outcome was executed, and by which tests
+#[derive(Debug, Clone, Default)]
+pub struct Hits {
+    pub count: u64,
+    /// Code run outside of a test (e.g. while a module is imported or in a before_all hook) isn't attributed to one
+    pub tests: BTreeSet<CoveringTest>,
+}
+
+impl Hits {
+    pub fn is_hit(&self) -> bool {
+        self.count > 0
+    }
+
+    fn record(&mut self, test: Option<&CoveringTest>) {
+        self.count += 1;
+        if let Some(test) = test {
+            if !self.tests.contains(test) {
+                self.tests.insert(test.clone());
+            }
+        }
+    }
+
+    fn merge(&mut self, other: &Hits) {
+        self.count += other.count;
+        self.tests.extend(other.tests.iter().cloned());
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FunctionCoverage {
     pub function_name: String,
+    /// Rhai functions can be overloaded by their number of parameters
+    pub arity: usize,
     #[allow(dead_code)]
     pub source: String,
     pub line_number: i64,
-    pub is_hit: bool,
+    pub hits: Hits,
 }
 
-#[derive(Debug)]
-struct StatementCoverage {
+#[derive(Debug, Clone)]
+pub struct StatementCoverage {
     #[allow(dead_code)]
     pub source: String,
     pub line_number: i64,
-    pub is_hit: bool,
+    #[allow(dead_code)]
+    pub column: i64,
+    pub hits: Hits,
 }
 
-#[derive(Debug)]
-struct BranchCoverage {
+/// A point where the code can go different ways (an `if`, a `switch`, a `??` or a `?.`), with each way it can go
+#[derive(Debug, Clone)]
+pub struct BranchCoverage {
     #[allow(dead_code)]
     pub source: String,
     pub line_number: i64,
-    pub is_hit: bool,
+    pub column: i64,
+    pub outcomes: Vec<BranchOutcome>,
+}
+
+#[derive(Debug, Clone)]
+pub struct BranchOutcome {
+    /// Describes the outcome in the report, e.g. `else` or `case 1 | 2`
+    pub label: String,
+    pub hits: Hits,
 }
 
 #[derive(Tabled)]
@@ -52,17 +206,23 @@ struct CoverageReportLine {
 
     #[tabled(rename = "Uncovered Line #s")]
     uncovered_lines: String,
+
+    #[tabled(rename = "Uncovered Branches")]
+    uncovered_branches: String,
 }
 
 #[derive(Debug)]
 pub struct TestCoverageContainer {
     sources: HashMap<String, TestCoverageSource>,
+    /// The test whose code is running, which everything hit gets attributed to
+    current_test: Option<CoveringTest>,
 }
 
 impl TestCoverageContainer {
     pub fn new() -> Self {
         Self {
             sources: HashMap::new(),
+            current_test: None,
         }
     }
 
@@ -73,6 +233,7 @@ impl TestCoverageContainer {
             self.sources.insert(
                 name.clone(),
                 TestCoverageSource {
+                    file_path: name.clone(),
                     name,
                     functions: HashMap::new(),
                     statements: HashMap::new(),
@@ -82,7 +243,20 @@ impl TestCoverageContainer {
         }
     }
 
-    pub fn add_function(&mut self, function_name: String, source: String, line_number: i64) {
+    /// Records which file a source was loaded from, so file based reports can point to it
+    pub fn set_source_file_path(&mut self, source: &str, file_path: String) {
+        let source = source.to_string();
+        self.maybe_add_source(&source);
+        self.sources.get_mut(&source).unwrap().file_path = file_path;
+    }
+
+    pub fn add_function(
+        &mut self,
+        function_name: String,
+        arity: usize,
+        source: String,
+        line_number: i64,
+    ) {
         self.maybe_add_source(&source);
         let key = TestCoverageContainer::get_function_key(&function_name, &source, &line_number);
 
@@ -93,15 +267,16 @@ impl TestCoverageContainer {
             .entry(key)
             .or_insert(FunctionCoverage {
                 function_name,
+                arity,
                 source,
                 line_number,
-                is_hit: false,
+                hits: Hits::default(),
             });
     }
 
-    pub fn add_statement(&mut self, source: String, line_number: i64) {
+    pub fn add_statement(&mut self, source: String, line_number: i64, column: i64) {
         self.maybe_add_source(&source);
-        let key = TestCoverageContainer::get_statement_key(&source, &line_number);
+        let key = TestCoverageContainer::get_statement_key(&source, &line_number, &column);
 
         self.sources
             .get_mut(&source)
@@ -111,13 +286,20 @@ impl TestCoverageContainer {
             .or_insert(StatementCoverage {
                 source,
                 line_number,
-                is_hit: false,
+                column,
+                hits: Hits::default(),
             });
     }
 
-    pub fn add_branch(&mut self, source: String, line_number: i64) {
+    pub fn add_branch(
+        &mut self,
+        source: String,
+        line_number: i64,
+        column: i64,
+        outcome_labels: Vec<String>,
+    ) {
         self.maybe_add_source(&source);
-        let key = TestCoverageContainer::get_statement_key(&source, &line_number);
+        let key = TestCoverageContainer::get_branch_key(&source, &line_number, &column);
 
         self.sources
             .get_mut(&source)
@@ -127,7 +309,14 @@ impl TestCoverageContainer {
             .or_insert(BranchCoverage {
                 source,
                 line_number,
-                is_hit: false,
+                column,
+                outcomes: outcome_labels
+                    .into_iter()
+                    .map(|label| BranchOutcome {
+                        label,
+                        hits: Hits::default(),
+                    })
+                    .collect(),
             });
     }
 
@@ -140,11 +329,12 @@ impl TestCoverageContainer {
             .functions
             .get_mut(&key)
             .unwrap()
-            .is_hit = true;
+            .hits
+            .record(self.current_test.as_ref());
     }
 
-    pub fn statement_called(&mut self, source: String, line_number: i64) {
-        let key = TestCoverageContainer::get_statement_key(&source, &line_number);
+    pub fn statement_called(&mut self, source: String, line_number: i64, column: i64) {
+        let key = TestCoverageContainer::get_statement_key(&source, &line_number, &column);
 
         self.sources
             .get_mut(&source)
@@ -152,11 +342,12 @@ impl TestCoverageContainer {
             .statements
             .get_mut(&key)
             .unwrap()
-            .is_hit = true;
+            .hits
+            .record(self.current_test.as_ref());
     }
 
-    pub fn branch_called(&mut self, source: String, line_number: i64) {
-        let key = TestCoverageContainer::get_statement_key(&source, &line_number);
+    pub fn branch_called(&mut self, source: String, line_number: i64, column: i64, outcome: usize) {
+        let key = TestCoverageContainer::get_branch_key(&source, &line_number, &column);
 
         self.sources
             .get_mut(&source)
@@ -164,88 +355,150 @@ impl TestCoverageContainer {
             .branches
             .get_mut(&key)
             .unwrap()
-            .is_hit = true;
+            .outcomes[outcome]
+            .hits
+            .record(self.current_test.as_ref());
+    }
+
+    /// Records which outcome of a `??` or `?.` was taken from the value of one of its operands.
+    /// A value other than `()` is used, so that operand's outcome was taken. Otherwise evaluation moves on to the next
+    /// operand, which is the outcome taken when it's the last one (there's nothing left to check its value).
+    pub fn branch_value_called(
+        &mut self,
+        source: String,
+        line_number: i64,
+        column: i64,
+        outcome: usize,
+        is_unit: bool,
+    ) {
+        let key = TestCoverageContainer::get_branch_key(&source, &line_number, &column);
+        let outcomes = &mut self
+            .sources
+            .get_mut(&source)
+            .unwrap()
+            .branches
+            .get_mut(&key)
+            .unwrap()
+            .outcomes;
+
+        if !is_unit {
+            outcomes[outcome].hits.record(self.current_test.as_ref());
+        } else if outcome + 2 == outcomes.len() {
+            outcomes[outcome + 1]
+                .hits
+                .record(self.current_test.as_ref());
+        }
+    }
+
+    /// Sets the test that everything hit from now on gets attributed to (None when no test is running)
+    pub fn set_current_test(&mut self, test: Option<CoveringTest>) {
+        self.current_test = test;
+    }
+
+    /// Merges the coverage collected by another container (e.g. from a test file run on another thread) into this one.
+    /// The hits of both containers are added up.
+    pub fn merge(&mut self, other: &TestCoverageContainer) {
+        for (name, other_source) in &other.sources {
+            self.maybe_add_source(name);
+            let source = self.sources.get_mut(name).unwrap();
+            source.file_path = other_source.file_path.clone();
+
+            for (key, function) in &other_source.functions {
+                source
+                    .functions
+                    .entry(key.clone())
+                    .and_modify(|existing| existing.hits.merge(&function.hits))
+                    .or_insert_with(|| function.clone());
+            }
+            for (key, statement) in &other_source.statements {
+                source
+                    .statements
+                    .entry(key.clone())
+                    .and_modify(|existing| existing.hits.merge(&statement.hits))
+                    .or_insert_with(|| statement.clone());
+            }
+            for (key, branch) in &other_source.branches {
+                source
+                    .branches
+                    .entry(key.clone())
+                    .and_modify(|existing| {
+                        for (outcome, other_outcome) in
+                            existing.outcomes.iter_mut().zip(&branch.outcomes)
+                        {
+                            outcome.hits.merge(&other_outcome.hits);
+                        }
+                    })
+                    .or_insert_with(|| branch.clone());
+            }
+        }
+    }
+
+    /// Returns the sources ordered by their name
+    pub fn get_sorted_sources(&self) -> Vec<&TestCoverageSource> {
+        let mut sources = self.sources.values().collect::<Vec<_>>();
+        sources.sort_by(|a, b| a.name.cmp(&b.name));
+        sources
     }
 
     fn get_function_key(function_name: &String, source: &String, line_number: &i64) -> String {
         format!("{}-{}-{}", function_name, source, line_number)
     }
 
-    fn get_statement_key(source: &String, line_number: &i64) -> String {
-        format!("{}-{}", source, line_number)
+    fn get_statement_key(source: &String, line_number: &i64, column: &i64) -> String {
+        format!("{}-{}-{}", source, line_number, column)
+    }
+
+    fn get_branch_key(source: &String, line_number: &i64, column: &i64) -> String {
+        format!("{}-{}-{}", source, line_number, column)
     }
 
     pub fn print_results(&mut self) {
         let mut report_data = Vec::<CoverageReportLine>::new();
 
-        self.sources.iter().for_each(|(_, coverage_source)| {
+        for coverage_source in self.get_sorted_sources() {
             let source = &coverage_source.name;
-            let percent_functions = {
-                let total_functions = coverage_source.functions.len();
-                let hit_functions = coverage_source
-                    .functions
-                    .iter()
-                    .filter(|(_, function)| function.is_hit)
-                    .count();
-                let percent = (hit_functions as f64 / total_functions as f64) * 100.0;
-                if percent >= 80.0 {
-                    percent.to_string().green()
-                } else if percent >= 50.0 {
-                    percent.to_string().yellow()
-                } else {
-                    percent.to_string().red()
-                }
-            };
-            let percent_statements = {
-                let total_statements = coverage_source.statements.len();
-                let hit_statements = coverage_source
-                    .statements
-                    .iter()
-                    .filter(|(_, statement)| statement.is_hit)
-                    .count();
-                let percent = (hit_statements as f64 / total_statements as f64) * 100.0;
-                if percent >= 80.0 {
-                    percent.to_string().green()
-                } else if percent >= 50.0 {
-                    percent.to_string().yellow()
-                } else {
-                    percent.to_string().red()
-                }
-            };
-            let percent_branches = {
-                let total_branches = coverage_source.branches.len();
-                let hit_branches = coverage_source
-                    .branches
-                    .iter()
-                    .filter(|(_, branch)| branch.is_hit)
-                    .count();
-                let percent = (hit_branches as f64 / total_branches as f64) * 100.0;
-                if percent >= 80.0 {
-                    percent.to_string().green()
-                } else if percent >= 50.0 {
-                    percent.to_string().yellow()
-                } else {
-                    percent.to_string().red()
-                }
-            };
+            let summary = coverage_source.summary();
+            let percent_functions = format_percent(summary.functions.percent());
+            let percent_statements = format_percent(summary.statements.percent());
+            let percent_branches = format_percent(summary.branches.percent());
             let uncovered_statements = coverage_source
                 .statements
                 .iter()
-                .filter(|(_, statement)| !statement.is_hit)
+                .filter(|(_, statement)| !statement.hits.is_hit())
                 .map(|(_, statement)| statement.line_number)
                 .collect::<Vec<_>>();
             let uncovered_functions = coverage_source
                 .functions
                 .iter()
-                .filter(|(_, function)| !function.is_hit)
+                .filter(|(_, function)| !function.hits.is_hit())
                 .map(|(_, function)| function.line_number)
                 .collect::<Vec<_>>();
-            let uncovered_branches = coverage_source
+            let mut partially_covered_branches = coverage_source
                 .branches
+                .values()
+                .filter(|branch| branch.outcomes.iter().any(|outcome| !outcome.hits.is_hit()))
+                .collect::<Vec<_>>();
+            partially_covered_branches.sort_by_key(|branch| (branch.line_number, branch.column));
+            let uncovered_branches = partially_covered_branches
                 .iter()
-                .filter(|(_, branch)| !branch.is_hit)
-                .map(|(_, branch)| branch.line_number)
+                .map(|branch| branch.line_number)
                 .collect::<Vec<_>>();
+            let uncovered_branch_outcomes = partially_covered_branches
+                .iter()
+                .flat_map(|branch| {
+                    branch
+                        .outcomes
+                        .iter()
+                        .filter(|outcome| !outcome.hits.is_hit())
+                        .map(|outcome| {
+                            format!(
+                                "{}:{} ({})",
+                                branch.line_number, branch.column, outcome.label
+                            )
+                        })
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
 
             let uncovered_lines = {
                 let mut uncovered_line_numbers = uncovered_statements.clone();
@@ -266,10 +519,23 @@ impl TestCoverageContainer {
                 branches: percent_branches.to_string(),
                 functions: percent_functions.to_string(),
                 uncovered_lines: uncovered_lines.to_string(),
+                uncovered_branches: uncovered_branch_outcomes,
             });
-        });
+        }
 
         let table = Table::new(report_data).with(Style::modern()).to_string();
         println!("\n\n{}", table);
     }
 }
+
+/// Formats a coverage percentage for the terminal table, coloured by how well covered it is
+fn format_percent(percent: f64) -> ColoredString {
+    let formatted = format!("{:.2}", percent);
+    if percent >= 80.0 {
+        formatted.green()
+    } else if percent >= 50.0 {
+        formatted.yellow()
+    } else {
+        formatted.red()
+    }
+}
@@ -1,4 +1,4 @@
-use super::logging_container::LoggingContainer;
+use super::{logging_container::LoggingContainer, test_limits::TestLimiter};
 use crate::{
     coverage_reporting::{
         file_coverage_module_resolver::FileCoverageModuleResolver,
@@ -20,6 +20,7 @@ pub fn create_engine(
     config: Arc<Mutex<Config>>,
     module_cache: Arc<Mutex<BTreeMap<PathBuf, Arc<Module>>>>,
     logging_container: Arc<Mutex<LoggingContainer>>,
+    test_limiter: Arc<TestLimiter>,
 ) -> Engine {
     let mut engine = Engine::new();
     let coverage = config.lock().unwrap().coverage;
@@ -38,6 +39,12 @@ pub fn create_engine(
         engine.set_module_resolver(resolver);
     }
 
+    // Stop scripts that go over the limits of the test being run, so an infinite loop fails the test rather than hanging the run
+    if let Some(max_operations) = test_limiter.get_max_operations() {
+        engine.set_max_operations(max_operations);
+    }
+    engine.on_progress(move |_| test_limiter.check());
+
     // Register all our functions and mocks
     extensions::apollo::register_rhai_functions_and_types(&mut engine, logging_container);
     extensions::helpers::register_rhai_functions_and_types(&mut engine);
@@ -2,17 +2,24 @@ use super::{
     error_handling::{get_inner_most_error, get_stack_trace, get_stack_trace_output},
     logging_container::{LogLevel, LoggingContainer},
     test_container::TestContainer,
+    test_limits::TestLimiter,
 };
 use crate::coverage_reporting::test_coverage_container::TestCoverageContainer;
 use crate::engine::engine::create_engine;
 use crate::Config;
 use http::{HeaderMap, Uri};
 use regex::Regex;
-use rhai::{Dynamic, EvalAltResult, FnPtr, ImmutableString, Module, AST};
+use rhai::{
+    Array, Blob, Dynamic, EvalAltResult, FnPtr, ImmutableString, Map, Module, NativeCallContext,
+    AST, FLOAT,
+};
 use std::{
+    cmp::Ordering,
     collections::BTreeMap,
+    fmt,
     path::PathBuf,
     sync::{Arc, Mutex},
+    time::Instant,
 };
 
 /// Represents all the different types of values that can be passed to an expect() or one of its functions
@@ -21,12 +28,20 @@ pub enum ExpectedValue {
     String(String),
     Bool(bool),
     Int(i64),
+    Float(FLOAT),
+    Char(char),
+    /// Rhai arrays, compared item by item
+    Array(Vec<ExpectedValue>),
+    /// Rhai object maps, compared key by key
+    Map(BTreeMap<String, ExpectedValue>),
+    Blob(Blob),
+    Timestamp(Instant),
     Function(FnPtr),
     Nothing(()),
     LogLevel(LogLevel),
     /// Rhai `HeaderMap` (e.g. from `request.headers`).
     HeaderMap(HeaderMap),
-    /// Any other Rhai value (timestamps, shared mocks, etc.) — supports `to_exist` / `not().to_exist`.
+    /// Any other Rhai value (shared mocks, etc.) — supports `to_exist` / `not().to_exist`.
     Present,
 }
 
@@ -40,6 +55,22 @@ impl ExpectedValue {
             ExpectedValue::Bool(b)
         } else if let Some(i) = dynamic.clone().try_cast::<i64>() {
             ExpectedValue::Int(i)
+        } else if let Some(f) = dynamic.clone().try_cast::<FLOAT>() {
+            ExpectedValue::Float(f)
+        } else if let Some(c) = dynamic.clone().try_cast::<char>() {
+            ExpectedValue::Char(c)
+        } else if let Some(a) = dynamic.clone().try_cast::<Array>() {
+            ExpectedValue::Array(a.iter().map(ExpectedValue::from_dynamic).collect())
+        } else if let Some(m) = dynamic.clone().try_cast::<Map>() {
+            ExpectedValue::Map(
+                m.iter()
+                    .map(|(key, value)| (key.to_string(), ExpectedValue::from_dynamic(value)))
+                    .collect(),
+            )
+        } else if let Some(b) = dynamic.clone().try_cast::<Blob>() {
+            ExpectedValue::Blob(b)
+        } else if let Some(t) = dynamic.clone().try_cast::<Instant>() {
+            ExpectedValue::Timestamp(t)
         } else if let Some(f) = dynamic.clone().try_cast::<FnPtr>() {
             ExpectedValue::Function(f)
         } else if let Some(n) = dynamic.clone().try_cast::<()>() {
@@ -54,6 +85,35 @@ impl ExpectedValue {
             ExpectedValue::Present
         }
     }
+
+    /// What kind of value this is, for failure messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ExpectedValue::String(_) => "string",
+            ExpectedValue::Bool(_) => "bool",
+            ExpectedValue::Int(_) => "int",
+            ExpectedValue::Float(_) => "float",
+            ExpectedValue::Char(_) => "char",
+            ExpectedValue::Array(_) => "array",
+            ExpectedValue::Map(_) => "map",
+            ExpectedValue::Blob(_) => "blob",
+            ExpectedValue::Timestamp(_) => "timestamp",
+            ExpectedValue::Function(_) => "function",
+            ExpectedValue::Nothing(_) => "()",
+            ExpectedValue::LogLevel(_) => "logging function",
+            ExpectedValue::HeaderMap(_) => "header map",
+            ExpectedValue::Present => "value",
+        }
+    }
+
+    /// The type name with an article, e.g. "an array"
+    pub fn type_description(&self) -> String {
+        match self.type_name() {
+            "()" => "()".to_string(),
+            name if name.starts_with(['a', 'e', 'i', 'o', 'u']) => format!("an {}", name),
+            name => format!("a {}", name),
+        }
+    }
 }
 
 /// Defines how to compare these enum values to each other
@@ -63,6 +123,12 @@ impl PartialEq for ExpectedValue {
             (ExpectedValue::String(s1), ExpectedValue::String(s2)) => s1 == s2,
             (ExpectedValue::Bool(b1), ExpectedValue::Bool(b2)) => b1 == b2,
             (ExpectedValue::Int(i1), ExpectedValue::Int(i2)) => i1 == i2,
+            (ExpectedValue::Float(f1), ExpectedValue::Float(f2)) => f1 == f2,
+            (ExpectedValue::Char(c1), ExpectedValue::Char(c2)) => c1 == c2,
+            (ExpectedValue::Array(a1), ExpectedValue::Array(a2)) => a1 == a2,
+            (ExpectedValue::Map(m1), ExpectedValue::Map(m2)) => m1 == m2,
+            (ExpectedValue::Blob(b1), ExpectedValue::Blob(b2)) => b1 == b2,
+            (ExpectedValue::Timestamp(t1), ExpectedValue::Timestamp(t2)) => t1 == t2,
             (ExpectedValue::Function(f1), ExpectedValue::Function(f2)) => {
                 f1.to_string() == f2.to_string()
             }
@@ -76,9 +142,220 @@ impl PartialEq for ExpectedValue {
 // Don't remove this, this is required!
 impl Eq for ExpectedValue {}
 
+/// Shows values the way they're written in Rhai, which is how they're shown in the to_equal diff
+impl fmt::Display for ExpectedValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExpectedValue::String(s) => write!(f, "{:?}", s),
+            ExpectedValue::Bool(b) => write!(f, "{}", b),
+            ExpectedValue::Int(i) => write!(f, "{}", i),
+            ExpectedValue::Float(fl) => write!(f, "{:?}", fl),
+            ExpectedValue::Char(c) => write!(f, "{:?}", c),
+            ExpectedValue::Array(a) => {
+                let items = a.iter().map(|item| item.to_string()).collect::<Vec<_>>();
+                write!(f, "[{}]", items.join(", "))
+            }
+            ExpectedValue::Map(m) => {
+                let entries = m
+                    .iter()
+                    .map(|(key, value)| format!("{}: {}", format_map_key(key), value))
+                    .collect::<Vec<_>>();
+                write!(f, "#{{{}}}", entries.join(", "))
+            }
+            ExpectedValue::Blob(b) => write!(f, "blob {:?}", b),
+            ExpectedValue::Timestamp(t) => write!(f, "{:?}", t),
+            ExpectedValue::Function(fp) => write!(f, "{}", fp),
+            ExpectedValue::Nothing(_) => write!(f, "()"),
+            ExpectedValue::LogLevel(l) => write!(f, "{}", l.to_string()),
+            ExpectedValue::HeaderMap(h) => write!(f, "{:?}", h),
+            ExpectedValue::Present => write!(f, "<value>"),
+        }
+    }
+}
+
+/// Map keys that aren't valid identifiers have to be quoted, e.g. `#{"content-type": "json"}`
+fn format_map_key(key: &str) -> String {
+    let is_identifier = key
+        .chars()
+        .next()
+        .is_some_and(|c| c.is_alphabetic() || c == '_')
+        && key.chars().all(|c| c.is_alphanumeric() || c == '_');
+    if is_identifier {
+        key.to_string()
+    } else {
+        format!("{:?}", key)
+    }
+}
+
+/// Lists every place `actual` differs from `expected`, walking into maps and arrays so that each difference points at
+/// the key or index it's at (e.g. `value.items[2].name`).
+/// When partial, keys of `actual` maps that `expected` doesn't mention are ignored (arrays still have to be the same
+/// length).
+fn get_differences(
+    path: &str,
+    actual: &ExpectedValue,
+    expected: &ExpectedValue,
+    is_partial: bool,
+    differences: &mut Vec<String>,
+) {
+    match (actual, expected) {
+        (ExpectedValue::Map(actual_map), ExpectedValue::Map(expected_map)) => {
+            for (key, expected_value) in expected_map {
+                let key_path = get_key_path(path, key);
+                match actual_map.get(key) {
+                    Some(actual_value) => get_differences(
+                        &key_path,
+                        actual_value,
+                        expected_value,
+                        is_partial,
+                        differences,
+                    ),
+                    None => differences.push(format!(
+                        "{}: expected {} but the key is missing",
+                        key_path, expected_value
+                    )),
+                }
+            }
+            for (key, actual_value) in actual_map {
+                if !is_partial && !expected_map.contains_key(key) {
+                    differences.push(format!(
+                        "{}: unexpected key with value {}",
+                        get_key_path(path, key),
+                        actual_value
+                    ));
+                }
+            }
+        }
+        (ExpectedValue::Array(actual_array), ExpectedValue::Array(expected_array)) => {
+            for index in 0..actual_array.len().max(expected_array.len()) {
+                let index_path = format!("{}[{}]", path, index);
+                match (actual_array.get(index), expected_array.get(index)) {
+                    (Some(actual_item), Some(expected_item)) => get_differences(
+                        &index_path,
+                        actual_item,
+                        expected_item,
+                        is_partial,
+                        differences,
+                    ),
+                    (None, Some(expected_item)) => differences.push(format!(
+                        "{}: expected {} but the item is missing",
+                        index_path, expected_item
+                    )),
+                    (Some(actual_item), None) => {
+                        differences.push(format!("{}: unexpected item {}", index_path, actual_item))
+                    }
+                    (None, None) => (),
+                }
+            }
+        }
+        _ => {
+            if actual != expected {
+                differences.push(format!(
+                    "{}: expected {} but got {}",
+                    path, expected, actual
+                ));
+            }
+        }
+    }
+}
+
+/// Gets the number out of an int or a float, None for anything else
+fn get_number(value: &ExpectedValue) -> Option<FLOAT> {
+    match value {
+        ExpectedValue::Int(i) => Some(*i as FLOAT),
+        ExpectedValue::Float(f) => Some(*f),
+        _ => None,
+    }
+}
+
+/// Orders two numbers, comparing ints as ints so that large ones don't lose precision.
+/// None when either of them is NaN.
+fn compare_numbers(actual: &ExpectedValue, expected: &ExpectedValue) -> Option<Ordering> {
+    match (actual, expected) {
+        (ExpectedValue::Int(i1), ExpectedValue::Int(i2)) => Some(i1.cmp(i2)),
+        _ => get_number(actual)?.partial_cmp(&get_number(expected)?),
+    }
+}
+
+/// The shorter names that to_be_type() also accepts for some of Rhai's standard types, and the names the router's
+/// docs use for its types, which type_of() gives as Rust type paths
+fn get_type_name_alias(type_name: &str) -> Option<&'static str> {
+    match type_name {
+        "i64" => Some("int"),
+        "f64" => Some("float"),
+        "Fn" => Some("function"),
+        "()" => Some("unit"),
+        "apollo_router::context::Context" => Some("Context"),
+        _ if type_name.starts_with("http::header::map::HeaderMap") => Some("HeaderMap"),
+        // Requests and responses are shared, e.g. `alloc::sync::Arc<...<apollo_router::services::supergraph::Request>>>`
+        _ if type_name.starts_with("alloc::sync::Arc<") => {
+            let inner_type_name = type_name.trim_end_matches('>');
+
+            if inner_type_name.ends_with("Request") {
+                Some("Request")
+            } else if inner_type_name.ends_with("Response") {
+                Some("Response")
+            } else {
+                None
+            }
+        }
+        _ => None,
+    }
+}
+
+/// Every value of a header (a header can be set more than once), with the name matched case-insensitively
+fn get_header_values(headers: &HeaderMap, name: &str) -> Vec<String> {
+    headers
+        .get_all(name.to_lowercase().as_str())
+        .iter()
+        .map(|value| String::from_utf8_lossy(value.as_bytes()).to_string())
+        .collect()
+}
+
+/// Lists the headers one per line, indented under the failed test, so that failures show what was actually there
+fn format_headers(headers: &HeaderMap) -> String {
+    if headers.is_empty() {
+        return "\t\t  (no headers)".to_string();
+    }
+
+    headers
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "\t\t  {}: {}",
+                name,
+                String::from_utf8_lossy(value.as_bytes())
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Puts every difference on its own line, indented under the failed test
+fn format_differences(differences: &[String]) -> String {
+    differences
+        .iter()
+        .map(|difference| format!("\t\t  {}", difference))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn get_key_path(path: &str, key: &str) -> String {
+    let key = format_map_key(key);
+    if key.starts_with('"') {
+        format!("{}[{}]", path, key)
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Expector {
     pub value: ExpectedValue,
+    /// The value passed to expect() as is, which custom matchers are called with
+    actual: Dynamic,
+    /// The name of the value's type as `type_of()` would show it in a script (e.g. `map`, `i64` or `Request`)
+    pub type_name: String,
     pub negative: bool,
     ast: Option<Arc<Mutex<Option<AST>>>>,
     test_coverage_container: Option<Arc<Mutex<TestCoverageContainer>>>,
@@ -86,15 +363,18 @@ pub struct Expector {
     module_cache: Option<Arc<Mutex<BTreeMap<PathBuf, Arc<Module>>>>>,
     logging_container: Option<Arc<Mutex<LoggingContainer>>>,
     test_container: Option<Arc<Mutex<TestContainer>>>,
+    test_limiter: Option<Arc<TestLimiter>>,
 }
 
 impl Expector {
     /// We're going to attempt to parse a provided value into an expector. If it's an invalid value, it'll be given the Error enum type that we'll handle later in the expector functions.
-    pub fn new(value: Dynamic) -> Self {
+    pub fn new(value: Dynamic, type_name: String) -> Self {
         let value_from_dynamic = ExpectedValue::from_dynamic(&value);
 
         Self {
             value: value_from_dynamic,
+            actual: value,
+            type_name,
             negative: false,
             ast: None,
             test_coverage_container: None,
@@ -102,6 +382,7 @@ impl Expector {
             module_cache: None,
             logging_container: None,
             test_container: None,
+            test_limiter: None,
         }
     }
 
@@ -114,6 +395,7 @@ impl Expector {
         module_cache: Arc<Mutex<BTreeMap<PathBuf, Arc<Module>>>>,
         logging_container: Arc<Mutex<LoggingContainer>>,
         test_container: Arc<Mutex<TestContainer>>,
+        test_limiter: Arc<TestLimiter>,
     ) {
         self.ast = Some(ast);
         self.test_coverage_container = Some(test_coverage_container);
@@ -121,6 +403,7 @@ impl Expector {
         self.module_cache = Some(module_cache);
         self.logging_container = Some(logging_container);
         self.test_container = Some(test_container);
+        self.test_limiter = Some(test_limiter);
     }
 
     /// Inverses the check
@@ -168,6 +451,612 @@ impl Expector {
         }
     }
 
+    /// Checks if two values are structurally equal, walking into maps and arrays.
+    /// If this fails, it lists every key and index where the values differ
+    pub fn to_equal(&mut self, expected: Dynamic) {
+        let expected_val = ExpectedValue::from_dynamic(&expected);
+        let mut differences = Vec::new();
+        get_differences("value", &self.value, &expected_val, false, &mut differences);
+        let condition = differences.is_empty();
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected value to equal {} but it did not:\n{}",
+                expected_val,
+                format_differences(&differences)
+            ),
+            format!(
+                "Expected value {} to not equal {} but it did",
+                self.value, expected_val
+            ),
+        );
+    }
+
+    /// Adds a failed result, e.g. when the value passed to expect() isn't of a type the matcher can check
+    fn add_error(&mut self, error: String) {
+        self.test_container
+            .as_mut()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .add_expect_result(Result::Err(error));
+    }
+
+    /// Adds the result of a check: the error when it failed, the negative error when it passed but shouldn't have
+    /// (with not()), or a pass otherwise
+    fn add_result(&mut self, condition: bool, error: String, negative_error: String) {
+        let result = if !condition && !self.negative {
+            Result::Err(error)
+        } else if condition && self.negative {
+            Result::Err(negative_error)
+        } else {
+            Result::Ok(())
+        };
+
+        self.test_container
+            .as_mut()
+            .unwrap()
+            .lock()
+            .unwrap()
+            .add_expect_result(result);
+    }
+
+    /// Checks if a map (or array) contains the expected structure, ignoring any keys the expected map doesn't mention.
+    /// If this fails, it lists every key and index where the expected structure wasn't found
+    pub fn to_match_object(&mut self, expected: Dynamic) {
+        let expected_val = ExpectedValue::from_dynamic(&expected);
+        let is_matching_type = matches!(
+            (&self.value, &expected_val),
+            (ExpectedValue::Map(_), ExpectedValue::Map(_))
+                | (ExpectedValue::Array(_), ExpectedValue::Array(_))
+        );
+        if !is_matching_type {
+            self.add_error(
+                "Expected both the value passed to expect() and to to_match_object() to be maps or arrays"
+                    .to_string(),
+            );
+            return ();
+        }
+
+        let mut differences = Vec::new();
+        get_differences("value", &self.value, &expected_val, true, &mut differences);
+        let condition = differences.is_empty();
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected value to match object {} but it did not:\n{}",
+                expected_val,
+                format_differences(&differences)
+            ),
+            format!(
+                "Expected value {} to not match object {} but it did",
+                self.value, expected_val
+            ),
+        );
+    }
+
+    /// Checks if a map has a key whose value matches the expected value (maps within it are matched like
+    /// to_match_object, ignoring any keys the expected value doesn't mention)
+    pub fn to_contain_entry(&mut self, key: &str, expected: Dynamic) {
+        let expected_val = ExpectedValue::from_dynamic(&expected);
+        let map = match &self.value {
+            ExpectedValue::Map(map) => map,
+            _ => {
+                self.add_error("Expected value passed to expect() to be a map".to_string());
+                return ();
+            }
+        };
+
+        let key_path = get_key_path("value", key);
+        let mut differences = Vec::new();
+        match map.get(key) {
+            Some(actual_value) => get_differences(
+                &key_path,
+                actual_value,
+                &expected_val,
+                true,
+                &mut differences,
+            ),
+            None => differences.push(format!(
+                "{}: expected {} but the key is missing (the map has {})",
+                key_path,
+                expected_val,
+                if map.is_empty() {
+                    "no keys".to_string()
+                } else {
+                    format!(
+                        "keys {}",
+                        map.keys()
+                            .map(|key| format_map_key(key))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    )
+                }
+            )),
+        }
+        let condition = differences.is_empty();
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected value to contain entry {}: {} but it did not:\n{}",
+                format_map_key(key),
+                expected_val,
+                format_differences(&differences)
+            ),
+            format!(
+                "Expected value {} to not contain entry {}: {} but it did",
+                self.value,
+                format_map_key(key),
+                expected_val
+            ),
+        );
+    }
+
+    /// Checks if a number is greater than another
+    pub fn to_be_greater_than(&mut self, expected: Dynamic) {
+        self.compare("to_be_greater_than", "greater than", expected, |ordering| {
+            ordering == Ordering::Greater
+        });
+    }
+
+    /// Checks if a number is less than another
+    pub fn to_be_less_than(&mut self, expected: Dynamic) {
+        self.compare("to_be_less_than", "less than", expected, |ordering| {
+            ordering == Ordering::Less
+        });
+    }
+
+    /// Checks if a number is greater than or equal to another
+    pub fn to_be_greater_than_or_equal(&mut self, expected: Dynamic) {
+        self.compare(
+            "to_be_greater_than_or_equal",
+            "greater than or equal to",
+            expected,
+            |ordering| ordering != Ordering::Less,
+        );
+    }
+
+    /// Checks if a number is less than or equal to another
+    pub fn to_be_less_than_or_equal(&mut self, expected: Dynamic) {
+        self.compare(
+            "to_be_less_than_or_equal",
+            "less than or equal to",
+            expected,
+            |ordering| ordering != Ordering::Greater,
+        );
+    }
+
+    /// Checks if a number is ordered against another the way the matcher expects. Used internally by the to_be_*_than*
+    /// functions.
+    fn compare(
+        &mut self,
+        matcher: &str,
+        description: &str,
+        expected: Dynamic,
+        is_expected_ordering: fn(Ordering) -> bool,
+    ) {
+        let expected_val = ExpectedValue::from_dynamic(&expected);
+        if get_number(&self.value).is_none() || get_number(&expected_val).is_none() {
+            let error = format!(
+                "Expected both the value passed to expect() and to {}() to be numbers",
+                matcher
+            );
+
+            self.add_error(error);
+            return ();
+        }
+
+        // NaN isn't ordered against anything, so it never passes
+        let condition = compare_numbers(&self.value, &expected_val)
+            .map(is_expected_ordering)
+            .unwrap_or(false);
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected value {} to be {} {} but it was not",
+                self.value, description, expected_val
+            ),
+            format!(
+                "Expected value {} to not be {} {} but it was",
+                self.value, description, expected_val
+            ),
+        );
+    }
+
+    /// Checks if a number is within 2 decimal places of another, for floats that can't be compared exactly
+    pub fn to_be_close_to(&mut self, expected: Dynamic) {
+        self.to_be_close_to_with_precision(expected, 2);
+    }
+
+    /// Checks if a number is close to another, where the difference has to be less than half of 10 to the power of
+    /// minus the precision (like Jest's toBeCloseTo)
+    pub fn to_be_close_to_with_precision(&mut self, expected: Dynamic, precision: i64) {
+        let expected_val = ExpectedValue::from_dynamic(&expected);
+        let (actual_number, expected_number) = match (
+            get_number(&self.value),
+            get_number(&expected_val),
+        ) {
+            (Some(actual_number), Some(expected_number)) => (actual_number, expected_number),
+            _ => {
+                self.add_error(
+                    "Expected both the value passed to expect() and to to_be_close_to() to be numbers"
+                        .to_string(),
+                );
+                return ();
+            }
+        };
+
+        let difference = (actual_number - expected_number).abs();
+        let allowed_difference = (10 as FLOAT).powi(-(precision as i32)) / 2.0;
+        let condition = difference < allowed_difference
+            || (actual_number.is_infinite() && actual_number == expected_number);
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected value {} to be close to {} (precision {}, difference < {}) but the difference was {}",
+                self.value, expected_val, precision, allowed_difference, difference
+            ),
+            format!(
+                "Expected value {} to not be close to {} (precision {}, difference < {}) but the difference was {}",
+                self.value, expected_val, precision, allowed_difference, difference
+            ),
+        );
+    }
+
+    /// Checks if an array contains an item (compared like to_be), a string contains a substring or character, or a blob
+    /// contains a byte or a sequence of bytes
+    pub fn to_contain(&mut self, item: Dynamic) {
+        let item_val = ExpectedValue::from_dynamic(&item);
+
+        let condition = match (&self.value, &item_val) {
+            (ExpectedValue::Array(array), _) => array.contains(&item_val),
+            (ExpectedValue::String(string), ExpectedValue::String(substring)) => {
+                string.contains(substring.as_str())
+            }
+            (ExpectedValue::String(string), ExpectedValue::Char(c)) => string.contains(*c),
+            (ExpectedValue::Blob(blob), ExpectedValue::Int(byte)) => {
+                blob.iter().any(|b| *b as i64 == *byte)
+            }
+            (ExpectedValue::Blob(blob), ExpectedValue::Blob(bytes)) => {
+                bytes.is_empty() || blob.windows(bytes.len()).any(|window| window == bytes)
+            }
+            (ExpectedValue::String(_) | ExpectedValue::Blob(_), _) => {
+                let error = format!(
+                    "Expected the item passed to to_contain() to be {} when checking a {}, but it was {}",
+                    if let ExpectedValue::String(_) = &self.value {
+                        "a string or a char"
+                    } else {
+                        "an int (a byte) or a blob"
+                    },
+                    self.value.type_name(),
+                    item_val.type_description()
+                );
+
+                self.add_error(error);
+                return ();
+            }
+            _ => {
+                let error = format!(
+                    "Expected value passed to expect() to be an array, a string or a blob, but it was {}",
+                    self.value.type_description()
+                );
+
+                self.add_error(error);
+                return ();
+            }
+        };
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected {} {} to contain {} but it did not",
+                self.value.type_name(),
+                self.value,
+                item_val
+            ),
+            format!(
+                "Expected {} {} to not contain {} but it did",
+                self.value.type_name(),
+                self.value,
+                item_val
+            ),
+        );
+    }
+
+    /// Checks the number of items in an array, characters in a string, bytes in a blob or keys in a map
+    pub fn to_have_length(&mut self, expected_length: i64) {
+        let length = match self.get_length("to_have_length") {
+            Some(length) => length,
+            None => return (),
+        };
+        let condition = length as i64 == expected_length;
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected {} {} to have length {} but it had length {}",
+                self.value.type_name(),
+                self.value,
+                expected_length,
+                length
+            ),
+            format!(
+                "Expected {} {} to not have length {} but it did",
+                self.value.type_name(),
+                self.value,
+                expected_length
+            ),
+        );
+    }
+
+    /// Checks if an array, string, blob or map has nothing in it
+    pub fn to_be_empty(&mut self) {
+        let length = match self.get_length("to_be_empty") {
+            Some(length) => length,
+            None => return (),
+        };
+        let condition = length == 0;
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected {} {} to be empty but it had length {}",
+                self.value.type_name(),
+                self.value,
+                length
+            ),
+            format!(
+                "Expected {} {} to not be empty but it was",
+                self.value.type_name(),
+                self.value
+            ),
+        );
+    }
+
+    /// Gets the length of a value that has one, or adds a failed result when it doesn't. Used internally by
+    /// to_have_length and to_be_empty.
+    fn get_length(&mut self, matcher: &str) -> Option<usize> {
+        match &self.value {
+            ExpectedValue::Array(array) => Some(array.len()),
+            ExpectedValue::String(string) => Some(string.chars().count()),
+            ExpectedValue::Blob(blob) => Some(blob.len()),
+            ExpectedValue::Map(map) => Some(map.len()),
+            _ => {
+                let error = format!(
+                    "Expected value passed to expect() to be an array, a string, a blob or a map for {}(), but it was {}",
+                    matcher,
+                    self.value.type_description()
+                );
+
+                self.add_error(error);
+                None
+            }
+        }
+    }
+
+    /// Checks if a map has a key, whatever its value
+    pub fn to_have_key(&mut self, key: &str) {
+        let condition = match &self.value {
+            ExpectedValue::Map(map) => map.contains_key(key),
+            _ => {
+                let error = format!(
+                    "Expected value passed to expect() to be a map, but it was {}",
+                    self.value.type_description()
+                );
+
+                self.add_error(error);
+                return ();
+            }
+        };
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected map {} to have key {} but it did not",
+                self.value,
+                format_map_key(key)
+            ),
+            format!(
+                "Expected map {} to not have key {} but it did",
+                self.value,
+                format_map_key(key)
+            ),
+        );
+    }
+
+    /// Checks the type of a value, by the name `type_of()` gives it in a script (e.g. `map` or `string`). Type names are
+    /// matched case-insensitively, `int`, `float`, `function` and `unit` can be used for `i64`, `f64`, `Fn` and `()`, and
+    /// `Request`, `Response`, `HeaderMap` and `Context` for the router's types
+    pub fn to_be_type(&mut self, expected_type_name: &str) {
+        let condition = [
+            Some(self.type_name.as_str()),
+            get_type_name_alias(&self.type_name),
+        ]
+        .into_iter()
+        .flatten()
+        .any(|type_name| type_name.eq_ignore_ascii_case(expected_type_name));
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected value {} to be of type {} but it was of type {}",
+                self.value, expected_type_name, self.type_name
+            ),
+            format!(
+                "Expected value {} to not be of type {} but it was",
+                self.value, expected_type_name
+            ),
+        );
+    }
+
+    /// Checks if headers have a header, whatever its value. The name is matched case-insensitively
+    pub fn to_have_header(&mut self, name: &str) {
+        let headers = match self.get_headers("to_have_header") {
+            Some(headers) => headers,
+            None => return (),
+        };
+        let condition = !get_header_values(&headers, name).is_empty();
+
+        self.add_header_result(
+            condition,
+            format!("Expected headers to have header {}", name),
+            format!("Expected headers to not have header {}", name),
+            &headers,
+        );
+    }
+
+    /// Checks if headers have a header with a value. When the header is set more than once, any of its values can match
+    pub fn to_have_header_with_value(&mut self, name: &str, value: &str) {
+        let headers = match self.get_headers("to_have_header") {
+            Some(headers) => headers,
+            None => return (),
+        };
+        let condition = get_header_values(&headers, name)
+            .iter()
+            .any(|header_value| header_value == value);
+
+        self.add_header_result(
+            condition,
+            format!("Expected headers to have header {}: {}", name, value),
+            format!("Expected headers to not have header {}: {}", name, value),
+            &headers,
+        );
+    }
+
+    /// Checks if headers have a header with a value matching a regular expression. When the header is set more than
+    /// once, any of its values can match
+    pub fn to_have_header_matching(&mut self, name: &str, pattern: &str) {
+        let headers = match self.get_headers("to_have_header_matching") {
+            Some(headers) => headers,
+            None => return (),
+        };
+        let regex = match Regex::new(pattern) {
+            Ok(regex) => regex,
+            Err(error) => {
+                self.add_error(format!(
+                    "Expected the pattern passed to to_have_header_matching() to be a valid regular expression. Error: {}",
+                    error
+                ));
+                return ();
+            }
+        };
+        let condition = get_header_values(&headers, name)
+            .iter()
+            .any(|header_value| regex.is_match(header_value));
+
+        self.add_header_result(
+            condition,
+            format!(
+                "Expected headers to have header {} matching pattern {:?}",
+                name, pattern
+            ),
+            format!(
+                "Expected headers to not have header {} matching pattern {:?}",
+                name, pattern
+            ),
+            &headers,
+        );
+    }
+
+    /// Checks if headers have every header of a map, ignoring any other headers. A header expected to be set more than
+    /// once is given an array of values, which all have to be there
+    pub fn to_have_headers(&mut self, expected: Map) {
+        let headers = match self.get_headers("to_have_headers") {
+            Some(headers) => headers,
+            None => return (),
+        };
+        let expected_val = ExpectedValue::from_dynamic(&Dynamic::from_map(expected.clone()));
+
+        let mut differences = Vec::new();
+        for (name, expected_value) in &expected {
+            let expected_values = match expected_value.clone().try_cast::<Array>() {
+                Some(values) => values.iter().map(|value| value.to_string()).collect(),
+                None => vec![expected_value.to_string()],
+            };
+            let values = get_header_values(&headers, name);
+            if values.is_empty() {
+                differences.push(format!("{}: the header is missing", name));
+                continue;
+            }
+
+            for expected_value in expected_values {
+                if !values.contains(&expected_value) {
+                    differences.push(format!(
+                        "{}: expected {:?} but got {}",
+                        name,
+                        expected_value,
+                        values
+                            .iter()
+                            .map(|value| format!("{:?}", value))
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ));
+                }
+            }
+        }
+        let condition = differences.is_empty();
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected headers to have every header of {} but they did not:\n{}\n\t\tHeaders:\n{}",
+                expected_val,
+                format_differences(&differences),
+                format_headers(&headers)
+            ),
+            format!(
+                "Expected headers to not have every header of {} but they did",
+                expected_val
+            ),
+        );
+    }
+
+    /// Gets the headers passed to expect(), or adds a failed result when it wasn't a header map. Used internally by the
+    /// to_have_header* functions.
+    fn get_headers(&mut self, matcher: &str) -> Option<HeaderMap> {
+        match &self.value {
+            ExpectedValue::HeaderMap(headers) => Some(headers.clone()),
+            _ => {
+                let error = format!(
+                    "Expected value passed to expect() to be a header map for {}(), but it was {}",
+                    matcher,
+                    self.value.type_description()
+                );
+
+                self.add_error(error);
+                None
+            }
+        }
+    }
+
+    /// Adds the result of a header check, listing the actual headers when it failed
+    fn add_header_result(
+        &mut self,
+        condition: bool,
+        error: String,
+        negative_error: String,
+        headers: &HeaderMap,
+    ) {
+        self.add_result(
+            condition,
+            format!(
+                "{} but it did not. Headers:\n{}",
+                error,
+                format_headers(headers)
+            ),
+            format!(
+                "{} but it did. Headers:\n{}",
+                negative_error,
+                format_headers(headers)
+            ),
+        );
+    }
+
     /// Checks if a value exists (effectively, it's not ())
     pub fn to_exist(&mut self) {
         let condition: bool = if let ExpectedValue::Nothing(_) = &self.value {
@@ -456,6 +1345,7 @@ impl Expector {
         let config = self.config.clone().unwrap();
         let module_cache = self.module_cache.clone().unwrap();
         let logging_container = self.logging_container.clone().unwrap();
+        let test_limiter = self.test_limiter.clone().unwrap();
 
         // Why are we re-creating an engine here? Because the engine is already locked when this function is run, we end up in a thread-lock situation if we try to also use the engine here.
         // So the (unfortunate) solution is to re-create the engine
@@ -464,6 +1354,7 @@ impl Expector {
             config,
             module_cache,
             logging_container,
+            test_limiter.clone(),
         );
 
         let result = match &self.value {
@@ -475,6 +1366,9 @@ impl Expector {
         let mut status_code = String::new();
 
         if let Err(ref err) = result {
+            // This engine counts its own operations, so let the test know if they went over its budget
+            test_limiter.check_error(err);
+
             let stack_trace = get_stack_trace(err, None);
             message = stack_trace.last().unwrap().message.clone();
             status_code = stack_trace.last().unwrap().status_code.clone();
@@ -598,4 +1492,92 @@ impl Expector {
                 .add_expect_result(Result::Ok(()));
         }
     }
+
+    /// Checks if a value satisfies a predicate, which is called with the value and returns a bool. The predicate runs
+    /// within the test's own call (and AST), so unlike the to_throw* functions it doesn't need an engine of its own.
+    /// The description, when given, is shown in the failure message.
+    pub fn to_satisfy(
+        &mut self,
+        context: &NativeCallContext,
+        predicate: &FnPtr,
+        description: Option<&str>,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let result = predicate.call_within_context::<Dynamic>(context, (self.actual.clone(),))?;
+        let predicate_description = match description {
+            Some(description) => format!("the predicate '{}'", description),
+            None => "the predicate".to_string(),
+        };
+
+        let condition = match result.as_bool() {
+            Ok(condition) => condition,
+            Err(_) => {
+                let error = format!(
+                    "Expected {} passed to to_satisfy() to return a bool, but it returned {}",
+                    predicate_description,
+                    ExpectedValue::from_dynamic(&result).type_description()
+                );
+
+                self.add_error(error);
+                return Ok(());
+            }
+        };
+
+        self.add_result(
+            condition,
+            format!(
+                "Expected value {} to satisfy {}",
+                self.value, predicate_description
+            ),
+            format!(
+                "Expected value {} to not satisfy {}",
+                self.value, predicate_description
+            ),
+        );
+
+        Ok(())
+    }
+
+    /// Runs a matcher added with expect_extend(), calling its function with the value passed to expect() followed by
+    /// the matcher's own arguments. The function returns whether the value passed (`pass`) and what to show when the
+    /// matcher fails (`message`), which is used with and without not().
+    pub fn run_custom_matcher(
+        &mut self,
+        context: &NativeCallContext,
+        matcher: &str,
+        matcher_function: &FnPtr,
+        args: Vec<Dynamic>,
+    ) -> Result<(), Box<EvalAltResult>> {
+        let mut matcher_args = vec![self.actual.clone()];
+        matcher_args.extend(args);
+        let result = matcher_function.call_within_context::<Dynamic>(context, matcher_args)?;
+
+        let outcome = result.try_cast::<Map>().and_then(|outcome| {
+            let pass = outcome.get("pass")?.as_bool().ok()?;
+            let message = outcome
+                .get("message")
+                .map(|message| message.to_string())
+                .unwrap_or_default();
+            Some((pass, message))
+        });
+        let (condition, message) = match outcome {
+            Some(outcome) => outcome,
+            None => {
+                let error = format!(
+                    "Expected the {}() matcher to return a map with a pass flag and a message, e.g. #{{ pass: true, message: \"...\" }}",
+                    matcher
+                );
+
+                self.add_error(error);
+                return Ok(());
+            }
+        };
+
+        self.add_result(
+            condition,
+            format!("Expected value to pass {}(): {}", matcher, message),
+            format!("Expected value to not pass {}(): {}", matcher, message),
+        );
+
+        Ok(())
+    }
 }
@@ -2,5 +2,8 @@ pub(crate) mod engine;
 pub(crate) mod error_handling;
 pub(crate) mod expector;
 pub(crate) mod logging_container;
+pub(crate) mod parallel;
 pub(crate) mod test_container;
+pub(crate) mod test_file;
+pub(crate) mod test_limits;
 pub(crate) mod test_runner;
@@ -0,0 +1,58 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+/// Worker threads get the same stack size as the main thread so deeply recursive scripts behave the same either way
+const WORKER_STACK_SIZE: usize = 8 * 1024 * 1024;
+
+/// Calls `func` on every item using up to `jobs` worker threads, each picking up the next item as soon as it is free.
+/// Returns once every item has been processed.
+pub fn run_in_parallel<T, F>(items: &[Mutex<T>], jobs: usize, func: F)
+where
+    T: Send,
+    F: Fn(usize, &mut T) + Sync,
+{
+    let next_index = AtomicUsize::new(0);
+    let worker_count = jobs.clamp(1, items.len().max(1));
+
+    thread::scope(|scope| {
+        for _ in 0..worker_count {
+            thread::Builder::new()
+                .stack_size(WORKER_STACK_SIZE)
+                .spawn_scoped(scope, || loop {
+                    let index = next_index.fetch_add(1, Ordering::SeqCst);
+                    if index >= items.len() {
+                        break;
+                    }
+                    func(index, &mut items[index].lock().unwrap());
+                })
+                .expect("Unable to start a test worker thread");
+        }
+    });
+}
+
+/// Prints outputs in the order of their index, no matter in which order they finish.
+/// Each output is printed as soon as every output before it has been printed.
+pub struct OrderedPrinter {
+    next_index: usize,
+    pending: BTreeMap<usize, String>,
+}
+
+impl OrderedPrinter {
+    pub fn new() -> Self {
+        Self {
+            next_index: 0,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn print(&mut self, index: usize, output: String) {
+        self.pending.insert(index, output);
+
+        while let Some(output) = self.pending.remove(&self.next_index) {
+            print!("{}", output);
+            self.next_index += 1;
+        }
+    }
+}
@@ -1,29 +1,127 @@
+use super::{
+    error_handling::StackTraceDetail,
+    test_limits::TestLimits,
+    test_runner::{TestResult, TestStatus},
+};
 use colored::*;
 use rhai::FnPtr;
 use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+
+/// Separates describe() group names from each other and from the test name
+pub const GROUP_SEPARATOR: &str = " > ";
+
+/// How a test was declared: test(), test_only(), test_skip() or test_todo()
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestMode {
+    Normal,
+    Only,
+    Skip,
+    Todo,
+}
 
 #[derive(Debug, Clone)]
 pub struct Test {
     pub name: String,
-    pub test_function: FnPtr,
+    /// Todo tests are declared without a function
+    pub test_function: Option<FnPtr>,
     pub file_path: String,
+    pub mode: TestMode,
+    /// The names of the describe() blocks this test was declared in, outermost first
+    pub describe_path: Vec<String>,
+    /// Unique ids of the same describe() blocks, used to scope hooks (two blocks can share a name)
+    pub describe_ids: Vec<usize>,
+    /// Limits passed to test() for this test only, on top of the ones from the config file
+    pub limits: TestLimits,
 }
 
 impl Test {
-    fn new(name: String, test_function: FnPtr, file_path: String) -> Self {
+    fn new(
+        name: String,
+        test_function: Option<FnPtr>,
+        file_path: String,
+        mode: TestMode,
+        describe_path: Vec<String>,
+        describe_ids: Vec<usize>,
+        limits: TestLimits,
+    ) -> Self {
         Self {
             name,
             test_function,
             file_path,
+            mode,
+            describe_path,
+            describe_ids,
+            limits,
+        }
+    }
+
+    /// The test name prefixed with the names of its describe() blocks
+    pub fn full_name(&self) -> String {
+        let mut parts = self.describe_path.clone();
+        parts.push(self.name.clone());
+        parts.join(GROUP_SEPARATOR)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HookKind {
+    BeforeAll,
+    BeforeEach,
+    AfterEach,
+    AfterAll,
+}
+
+impl HookKind {
+    pub fn all() -> [HookKind; 4] {
+        [
+            HookKind::BeforeAll,
+            HookKind::BeforeEach,
+            HookKind::AfterEach,
+            HookKind::AfterAll,
+        ]
+    }
+}
+
+impl fmt::Display for HookKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookKind::BeforeAll => write!(f, "before_all"),
+            HookKind::BeforeEach => write!(f, "before_each"),
+            HookKind::AfterEach => write!(f, "after_each"),
+            HookKind::AfterAll => write!(f, "after_all"),
         }
     }
 }
 
+/// A lifecycle hook, scoped to the describe() block it was declared in (or the whole file when `describe_id` is None)
+#[derive(Debug, Clone)]
+pub struct Hook {
+    pub kind: HookKind,
+    pub hook_function: FnPtr,
+    pub file_path: String,
+    pub describe_id: Option<usize>,
+}
+
+/// A matcher added with expect_extend(), which becomes callable on any expect() once the file's tests run
+#[derive(Debug, Clone)]
+pub struct CustomMatcher {
+    pub name: String,
+    pub matcher_function: FnPtr,
+}
+
 #[derive(Debug, Clone)]
 pub struct TestSuite {
-    #[allow(dead_code)]
-    file_path: String,
-    is_passed: bool,
+    pub file_path: String,
+    pub is_passed: bool,
+    /// None of the suite's tests were run (they were all skipped or todo)
+    pub is_skipped: bool,
+    pub test_results: Vec<TestResult>,
+    /// Set when the suite could not be compiled or evaluated, so none of its tests ran
+    pub error: Option<String>,
+    pub error_stack_trace: Vec<StackTraceDetail>,
+    pub duration: Duration,
 }
 
 impl TestSuite {
@@ -31,6 +129,11 @@ impl TestSuite {
         Self {
             file_path: file_path.to_string(),
             is_passed: true,
+            is_skipped: false,
+            test_results: Vec::new(),
+            error: None,
+            error_stack_trace: Vec::new(),
+            duration: Duration::ZERO,
         }
     }
 }
@@ -41,7 +144,16 @@ pub struct TestContainer {
     pub test_suites: HashMap<String, TestSuite>,
     pub passed_tests: i32,
     pub failed_tests: i32,
+    pub skipped_tests: i32,
+    pub todo_tests: i32,
     pub expect_results: Vec<Result<(), String>>,
+    pub hooks: Vec<Hook>,
+    pub custom_matchers: Vec<CustomMatcher>,
+    /// Set once the custom matchers became functions of expect(), after which expect_extend() can't add any more
+    are_custom_matchers_registered: bool,
+    /// The describe() blocks (id and name) we are currently inside of while evaluating a test file
+    describe_stack: Vec<(usize, String)>,
+    next_describe_id: usize,
 }
 
 impl TestContainer {
@@ -51,7 +163,14 @@ impl TestContainer {
             test_suites: HashMap::new(),
             passed_tests: 0,
             failed_tests: 0,
+            skipped_tests: 0,
+            todo_tests: 0,
             expect_results: Vec::new(),
+            hooks: Vec::new(),
+            custom_matchers: Vec::new(),
+            are_custom_matchers_registered: false,
+            describe_stack: Vec::new(),
+            next_describe_id: 0,
         }
     }
 
@@ -62,15 +181,94 @@ impl TestContainer {
         }
     }
 
-    pub fn add_test(&mut self, name: &str, func: FnPtr, file_path: &str) {
-        self.tests
-            .push(Test::new(name.to_string(), func, file_path.to_string()));
+    pub fn add_test(
+        &mut self,
+        name: &str,
+        func: Option<FnPtr>,
+        file_path: &str,
+        mode: TestMode,
+        limits: TestLimits,
+    ) {
+        self.tests.push(Test::new(
+            name.to_string(),
+            func,
+            file_path.to_string(),
+            mode,
+            self.describe_stack
+                .iter()
+                .map(|(_, name)| name.clone())
+                .collect(),
+            self.describe_stack.iter().map(|(id, _)| *id).collect(),
+            limits,
+        ));
+    }
+
+    pub fn add_hook(&mut self, kind: HookKind, func: FnPtr, file_path: &str) {
+        self.hooks.push(Hook {
+            kind,
+            hook_function: func,
+            file_path: file_path.to_string(),
+            describe_id: self.describe_stack.last().map(|(id, _)| *id),
+        });
+    }
+
+    /// Gets the hooks of a given kind declared in a file for a describe() block (or the top level of the file for None)
+    pub fn get_hooks(
+        &self,
+        file_path: &str,
+        kind: HookKind,
+        describe_id: Option<usize>,
+    ) -> Vec<Hook> {
+        self.hooks
+            .iter()
+            .filter(|hook| {
+                hook.file_path == file_path && hook.kind == kind && hook.describe_id == describe_id
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Adds a custom matcher, replacing any that was already added with the same name
+    pub fn add_custom_matcher(&mut self, name: &str, func: FnPtr) {
+        self.custom_matchers.retain(|matcher| matcher.name != name);
+        self.custom_matchers.push(CustomMatcher {
+            name: name.to_string(),
+            matcher_function: func,
+        });
+    }
+
+    pub fn get_custom_matchers(&self) -> &Vec<CustomMatcher> {
+        &self.custom_matchers
+    }
+
+    pub fn set_custom_matchers_registered(&mut self) {
+        self.are_custom_matchers_registered = true;
+    }
+
+    pub fn are_custom_matchers_registered(&self) -> bool {
+        self.are_custom_matchers_registered
+    }
+
+    /// Enters a describe() block, any tests or hooks added until the matching end_describe() will be grouped under it
+    pub fn begin_describe(&mut self, name: &str) {
+        self.describe_stack
+            .push((self.next_describe_id, name.to_string()));
+        self.next_describe_id += 1;
+    }
+
+    pub fn end_describe(&mut self) {
+        self.describe_stack.pop();
     }
 
     pub fn get_tests(&self) -> &Vec<Test> {
         &self.tests
     }
 
+    /// Whether any test in the whole run was declared with test_only()
+    pub fn has_only_tests(&self) -> bool {
+        self.tests.iter().any(|test| test.mode == TestMode::Only)
+    }
+
     pub fn has_failed_suites(&self) -> bool {
         self.test_suites
             .iter()
@@ -86,54 +284,125 @@ impl TestContainer {
             });
     }
 
+    /// Stores the individual test results of a suite so they can be written out by a reporter later
+    pub fn set_suite_results(
+        &mut self,
+        path: &str,
+        test_results: Vec<TestResult>,
+        duration: Duration,
+    ) {
+        self.test_suites
+            .entry(path.to_string())
+            .and_modify(|test_suite| {
+                test_suite.is_skipped = !test_results.is_empty()
+                    && test_results.iter().all(|test_result| {
+                        matches!(test_result.status, TestStatus::Skipped | TestStatus::Todo)
+                    });
+                test_suite.test_results = test_results;
+                test_suite.duration = duration;
+            });
+    }
+
+    /// Fails a suite that could not be compiled or evaluated and records why
+    pub fn fail_suite_with_error(
+        &mut self,
+        path: &str,
+        error: String,
+        stack_trace: Vec<StackTraceDetail>,
+    ) {
+        self.test_suites
+            .entry(path.to_string())
+            .and_modify(|test_suite| {
+                test_suite.is_passed = false;
+                test_suite.error = Some(error);
+                test_suite.error_stack_trace = stack_trace;
+            });
+    }
+
+    /// Adds the tests, suites and counts of another container (e.g. from a test file run on another thread) to this one
+    pub fn merge(&mut self, other: TestContainer) {
+        self.tests.extend(other.tests);
+        self.test_suites.extend(other.test_suites);
+        self.passed_tests += other.passed_tests;
+        self.failed_tests += other.failed_tests;
+        self.skipped_tests += other.skipped_tests;
+        self.todo_tests += other.todo_tests;
+    }
+
+    /// Returns the test suites ordered by their file path
+    pub fn get_sorted_suites(&self) -> Vec<&TestSuite> {
+        let mut suites = self.test_suites.values().collect::<Vec<_>>();
+        suites.sort_by(|a, b| a.file_path.cmp(&b.file_path));
+        suites
+    }
+
     pub fn print_results(&mut self) {
         let count_passed_test_suites = self
             .test_suites
             .values()
-            .filter(|suite| suite.is_passed)
+            .filter(|suite| suite.is_passed && !suite.is_skipped)
             .count();
         let count_failed_test_suites = self
             .test_suites
             .values()
             .filter(|suite| !suite.is_passed)
             .count();
+        let count_skipped_test_suites = self
+            .test_suites
+            .values()
+            .filter(|suite| suite.is_passed && suite.is_skipped)
+            .count();
 
         println!("\r\n");
-        if count_failed_test_suites > 0 {
-            println!(
-                "Test Suites: {} {}, {} {}, {} total",
-                count_passed_test_suites.to_string().green(),
-                "passed".green(),
-                count_failed_test_suites.to_string().red(),
-                "failed".red(),
-                self.test_suites.len()
-            );
-        } else {
-            println!(
-                "Test Suites: {} {}, {} total",
-                count_passed_test_suites.to_string().green(),
-                "passed".green(),
-                self.test_suites.len()
-            );
-        }
+        println!(
+            "Test Suites: {}, {} total",
+            TestContainer::format_counts(
+                count_passed_test_suites,
+                count_failed_test_suites,
+                count_skipped_test_suites,
+                0
+            ),
+            self.test_suites.len()
+        );
+        println!(
+            "Tests:       {}, {} total",
+            TestContainer::format_counts(
+                self.passed_tests as usize,
+                self.failed_tests as usize,
+                self.skipped_tests as usize,
+                self.todo_tests as usize
+            ),
+            self.tests.len()
+        );
+    }
 
-        if self.failed_tests > 0 {
-            println!(
-                "Tests:       {} {}, {} {}, {} total",
-                self.passed_tests.to_string().green(),
-                "passed".green(),
-                self.failed_tests.to_string().red(),
-                "failed".red(),
-                self.tests.len()
-            );
-        } else {
-            println!(
-                "Tests:       {} {}, {} total",
-                self.passed_tests.to_string().green(),
-                "passed".green(),
-                self.tests.len()
-            );
+    /// Formats the summary counts, leaving out failed/skipped/todo when there aren't any
+    fn format_counts(passed: usize, failed: usize, skipped: usize, todo: usize) -> String {
+        let mut counts = vec![format!(
+            "{} {}",
+            passed.to_string().green(),
+            "passed".green()
+        )];
+
+        if failed > 0 {
+            counts.push(format!("{} {}", failed.to_string().red(), "failed".red()));
+        }
+        if skipped > 0 {
+            counts.push(format!(
+                "{} {}",
+                skipped.to_string().yellow(),
+                "skipped".yellow()
+            ));
         }
+        if todo > 0 {
+            counts.push(format!(
+                "{} {}",
+                todo.to_string().magenta(),
+                "todo".magenta()
+            ));
+        }
+
+        counts.join(", ")
     }
 
     pub fn add_expect_result(&mut self, result: Result<(), String>) {
@@ -0,0 +1,512 @@
+use super::{
+    engine::create_engine,
+    error_handling::{get_stack_trace, get_stack_trace_output, StackTraceDetail},
+    expector::Expector,
+    logging_container::LoggingContainer,
+    test_container::{CustomMatcher, HookKind, TestContainer, TestMode},
+    test_limits::{TestLimiter, TestLimits},
+    test_runner::TestRunner,
+};
+use crate::coverage_reporting::test_coverage_container::TestCoverageContainer;
+use crate::Config;
+use colored::*;
+use regex::Regex;
+use rhai::{
+    Dynamic, Engine, EvalAltResult, FnPtr, Map, Module, NativeCallContext, ParseError, AST,
+};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A test file's AST once evaluated, or the error output and stack trace of why it couldn't be
+type EvaluatedFile = Result<AST, (String, Vec<StackTraceDetail>)>;
+
+/// Everything needed to evaluate and run a single test file.
+/// Each file gets its own engine and containers so that files can run in parallel without sharing any state.
+pub struct TestFile {
+    pub path: String,
+    engine: Engine,
+    shared_ast: Arc<Mutex<Option<AST>>>,
+    logging_container: Arc<Mutex<LoggingContainer>>,
+    pub test_container: Arc<Mutex<TestContainer>>,
+    pub test_coverage_container: Arc<Mutex<TestCoverageContainer>>,
+    test_limiter: Arc<TestLimiter>,
+    /// The limits from the config file, used for anything that doesn't set its own
+    default_limits: TestLimits,
+    evaluated_file: Option<EvaluatedFile>,
+}
+
+impl TestFile {
+    pub fn new(path: &str, config: Arc<Mutex<Config>>) -> Self {
+        // Create all our core engine objects
+        let test_container = Arc::new(Mutex::new(TestContainer::new()));
+        let test_coverage_container = Arc::new(Mutex::new(TestCoverageContainer::new()));
+        let module_cache = Arc::new(Mutex::new(BTreeMap::<PathBuf, Arc<Module>>::new()));
+        let logging_container = Arc::new(Mutex::new(LoggingContainer::new()));
+        let test_limiter = Arc::new(TestLimiter::new());
+        let default_limits = {
+            let config = config.lock().unwrap();
+            TestLimits {
+                timeout: config.test_timeout,
+                max_operations: config.max_operations,
+            }
+        };
+        let mut engine = create_engine(
+            test_coverage_container.clone(),
+            config.clone(),
+            module_cache.clone(),
+            logging_container.clone(),
+            test_limiter.clone(),
+        );
+        let shared_ast: Arc<Mutex<Option<AST>>> = Arc::new(Mutex::new(None));
+
+        // We're cloning stuff here so that it can be moved into the expect()` closure below
+        let cloned_shared_ast = shared_ast.clone();
+        let test_coverage_container_clone = test_coverage_container.clone();
+        let cloned_logging_container = logging_container.clone();
+        let cloned_container = test_container.clone();
+        let cloned_describe_container = test_container.clone();
+        let cloned_test_limiter = test_limiter.clone();
+
+        // Attach the test specific functions to the engine including defining our expect() function
+        engine
+            .register_type_with_name::<Expector>("Expector")
+            .register_fn(
+                "expect",
+                move |context: NativeCallContext, value: Dynamic| {
+                    // Named the way type_of() names it
+                    let engine = context.engine();
+                    let type_name = engine.map_type_name(value.type_name()).to_string();
+                    let mut expector = Expector::new(value, type_name);
+                    expector.attach(
+                        cloned_shared_ast.clone(),
+                        test_coverage_container_clone.clone(),
+                        config.clone(),
+                        module_cache.clone(),
+                        cloned_logging_container.clone(),
+                        cloned_container.clone(),
+                        cloned_test_limiter.clone(),
+                    );
+                    expector
+                },
+            )
+            .register_fn("not", Expector::not)
+            .register_fn("to_be", Expector::to_be)
+            .register_fn("to_equal", Expector::to_equal)
+            .register_fn("to_match_object", Expector::to_match_object)
+            .register_fn("to_contain_entry", Expector::to_contain_entry)
+            .register_fn("to_be_greater_than", Expector::to_be_greater_than)
+            .register_fn("to_be_less_than", Expector::to_be_less_than)
+            .register_fn(
+                "to_be_greater_than_or_equal",
+                Expector::to_be_greater_than_or_equal,
+            )
+            .register_fn(
+                "to_be_less_than_or_equal",
+                Expector::to_be_less_than_or_equal,
+            )
+            .register_fn("to_be_close_to", Expector::to_be_close_to)
+            .register_fn("to_be_close_to", Expector::to_be_close_to_with_precision)
+            .register_fn("to_contain", Expector::to_contain)
+            .register_fn("to_have_length", Expector::to_have_length)
+            .register_fn("to_have_key", Expector::to_have_key)
+            .register_fn("to_be_empty", Expector::to_be_empty)
+            .register_fn("to_be_type", Expector::to_be_type)
+            .register_fn("to_have_header", Expector::to_have_header)
+            .register_fn("to_have_header", Expector::to_have_header_with_value)
+            .register_fn("to_have_header_matching", Expector::to_have_header_matching)
+            .register_fn("to_have_headers", Expector::to_have_headers)
+            .register_fn(
+                "to_satisfy",
+                |context: NativeCallContext, expector: &mut Expector, predicate: FnPtr| {
+                    expector.to_satisfy(&context, &predicate, None)
+                },
+            )
+            .register_fn(
+                "to_satisfy",
+                |context: NativeCallContext,
+                 expector: &mut Expector,
+                 predicate: FnPtr,
+                 description: &str| {
+                    expector.to_satisfy(&context, &predicate, Some(description))
+                },
+            )
+            .register_fn("to_exist", Expector::to_exist)
+            .register_fn("to_match", Expector::to_match)
+            .register_fn("to_throw", Expector::to_throw)
+            .register_fn("to_throw_message", Expector::to_throw_message)
+            .register_fn("to_throw_status", Expector::to_throw_status)
+            .register_fn(
+                "to_throw_status_and_message",
+                Expector::to_throw_status_and_message,
+            )
+            .register_fn("to_log", Expector::to_log)
+            .register_fn("to_log_message", Expector::to_log_message);
+
+        // describe() runs its body straight away so that any test() calls inside of it get grouped under its name
+        engine.register_fn(
+            "describe",
+            move |context: NativeCallContext,
+                  name: &str,
+                  func: FnPtr|
+                  -> Result<(), Box<EvalAltResult>> {
+                cloned_describe_container
+                    .lock()
+                    .unwrap()
+                    .begin_describe(name);
+                let result = func.call_within_context::<Dynamic>(&context, ());
+                cloned_describe_container.lock().unwrap().end_describe();
+                result.map(|_| ())
+            },
+        );
+
+        // Add the test suite
+        test_container.lock().unwrap().add_suite(path);
+
+        // Create and register our test() functions, which can optionally be given limits as a third argument
+        for (function_name, test_mode) in [
+            ("test", TestMode::Normal),
+            ("test_only", TestMode::Only),
+            ("test_skip", TestMode::Skip),
+        ] {
+            // Clone these so they can be moved into the test() closures below
+            let cloned_container = test_container.clone();
+            let cloned_path = path.to_string();
+            let test = move |test_name: &str, func: FnPtr| {
+                cloned_container.lock().unwrap().add_test(
+                    test_name,
+                    Some(func),
+                    &cloned_path,
+                    test_mode,
+                    TestLimits::default(),
+                );
+            };
+            engine.register_fn(function_name, test);
+
+            let cloned_container = test_container.clone();
+            let cloned_path = path.to_string();
+            let test_with_options = move |test_name: &str,
+                                          func: FnPtr,
+                                          options: Map|
+                  -> Result<(), Box<EvalAltResult>> {
+                let limits = TestLimits::from_map(&options)?;
+                cloned_container.lock().unwrap().add_test(
+                    test_name,
+                    Some(func),
+                    &cloned_path,
+                    test_mode,
+                    limits,
+                );
+                Ok(())
+            };
+            engine.register_fn(function_name, test_with_options);
+        }
+
+        let cloned_container = test_container.clone();
+        let cloned_path = path.to_string();
+        let test_todo = move |test_name: &str| {
+            cloned_container.lock().unwrap().add_test(
+                test_name,
+                None,
+                &cloned_path,
+                TestMode::Todo,
+                TestLimits::default(),
+            );
+        };
+        engine.register_fn("test_todo", test_todo);
+
+        // Create and register our lifecycle hook functions (before_all(), before_each(), etc.)
+        for hook_kind in HookKind::all() {
+            let cloned_container = test_container.clone();
+            let cloned_path = path.to_string();
+            let hook = move |func: FnPtr| {
+                cloned_container
+                    .lock()
+                    .unwrap()
+                    .add_hook(hook_kind, func, &cloned_path);
+            };
+            engine.register_fn(hook_kind.to_string(), hook);
+        }
+
+        // Create and register expect_extend(), which adds a custom matcher to be registered once the file's tests run
+        let cloned_container = test_container.clone();
+        let expect_extend = move |context: NativeCallContext,
+                                  name: &str,
+                                  func: FnPtr|
+              -> Result<(), Box<EvalAltResult>> {
+            // Matchers are registered once the file is evaluated, so any added from a test or hook would never exist
+            if cloned_container
+                .lock()
+                .unwrap()
+                .are_custom_matchers_registered()
+            {
+                return Err(format!(
+                    "expect_extend() was called for '{}' from a test or hook, it has to be called outside of them (e.g. at the top of the test file)",
+                    name
+                )
+                .into());
+            }
+
+            if !rhai::is_valid_function_name(name) {
+                return Err(format!(
+                    "expect_extend() was given '{}', which is not a valid function name",
+                    name
+                )
+                .into());
+            }
+
+            // Don't let a custom matcher shadow one of ours (or any other registered function)
+            let signature_start = format!("{}(", name);
+            if context
+                .engine()
+                .gen_fn_signatures(false)
+                .iter()
+                .any(|signature| signature.starts_with(&signature_start))
+            {
+                return Err(format!(
+                    "expect_extend() was given '{}', which is already a registered function",
+                    name
+                )
+                .into());
+            }
+
+            // The function is given the value passed to expect() and up to 3 arguments, after any curried ones
+            let parameter_count = context
+                .iter_namespaces()
+                .flat_map(|namespace| namespace.iter_script_fn_info())
+                .filter(|(_, _, fn_name, _, _)| *fn_name == func.fn_name())
+                .map(|(_, _, _, parameter_count, _)| {
+                    parameter_count.saturating_sub(func.curry().len())
+                })
+                .min();
+            if let Some(parameter_count) = parameter_count.filter(|count| *count > 4) {
+                return Err(format!(
+                    "expect_extend() was given a function for '{}' with {} parameters, but a matcher's function can have at most 4 (the value passed to expect() and up to 3 arguments)",
+                    name, parameter_count
+                )
+                .into());
+            }
+
+            cloned_container
+                .lock()
+                .unwrap()
+                .add_custom_matcher(name, func);
+            Ok(())
+        };
+        engine.register_fn("expect_extend", expect_extend);
+
+        Self {
+            path: path.to_string(),
+            engine,
+            shared_ast,
+            logging_container,
+            test_container,
+            test_coverage_container,
+            test_limiter,
+            default_limits,
+            evaluated_file: None,
+        }
+    }
+
+    /// Parses and evaluates the test file, which registers all of its tests (but doesn't run them yet)
+    pub fn evaluate(&mut self) {
+        let test_file_content =
+            fs::read_to_string(&self.path).expect("Unable to read rhai test file");
+
+        let evaluated_file = match self.engine.compile(&test_file_content) {
+            Ok(ast) => {
+                // We're setting the AST into our shared_ast so that it can be used in our expect functions
+                *self.shared_ast.lock().unwrap() = Some(ast.clone());
+
+                // Now we can evaluate our test file (within the configured limits, as it can run code outside of tests too)
+                self.test_limiter
+                    .start(&mut self.engine, self.default_limits);
+                let eval_result = self.engine.eval::<()>(&test_file_content);
+                let exceeded_limit = self
+                    .test_limiter
+                    .stop(eval_result.as_ref().err().map(|error| &**error));
+
+                match eval_result {
+                    Ok(()) => Ok(ast),
+                    Err(error) => {
+                        // We failed evaluation... the test suite will be failed with this reason
+                        let stack_trace = get_stack_trace(&error, Some(self.path.clone()));
+                        let error_output = get_stack_trace_output(
+                            format!(
+                                "\t\t{}",
+                                exceeded_limit.unwrap_or(
+                                    "Unexpected error ocurred when running tests.".to_string()
+                                )
+                            ),
+                            &stack_trace,
+                        );
+                        Err((error_output, stack_trace))
+                    }
+                }
+            }
+            Err(error) => {
+                // We failed the AST step... the test suite will be failed with this reason
+                // Note that to get a proper stack trace, we need to convert the ParseError into a EvalAltResult
+                let ParseError(error_type, position) = error;
+                let rhai_error = rhai::EvalAltResult::ErrorParsing(*error_type, position);
+                let stack_trace = get_stack_trace(&Box::new(rhai_error), Some(self.path.clone()));
+                let error_output = get_stack_trace_output(
+                    "\t\tUnexpected error ocurred when compiling tests.".to_string(),
+                    &stack_trace,
+                );
+                Err((error_output, stack_trace))
+            }
+        };
+
+        self.evaluated_file = Some(evaluated_file);
+    }
+
+    /// Whether the file declared any test with test_only()
+    pub fn has_only_tests(&self) -> bool {
+        self.test_container.lock().unwrap().has_only_tests()
+    }
+
+    /// Registers every matcher added with expect_extend() as a function of Expector (e.g. `expect(x).to_be_admin()`).
+    /// Matchers can be given up to 3 arguments, which are passed to their function after the value given to expect().
+    fn register_custom_matchers(&mut self) {
+        let custom_matchers = {
+            let mut container = self.test_container.lock().unwrap();
+            container.set_custom_matchers_registered();
+            container.get_custom_matchers().clone()
+        };
+
+        for matcher in custom_matchers {
+            let CustomMatcher {
+                name,
+                matcher_function,
+            } = matcher;
+
+            let (cloned_name, cloned_function) = (name.clone(), matcher_function.clone());
+            self.engine.register_fn(
+                &name,
+                move |context: NativeCallContext, expector: &mut Expector| {
+                    expector.run_custom_matcher(&context, &cloned_name, &cloned_function, vec![])
+                },
+            );
+
+            let (cloned_name, cloned_function) = (name.clone(), matcher_function.clone());
+            self.engine.register_fn(
+                &name,
+                move |context: NativeCallContext, expector: &mut Expector, arg1: Dynamic| {
+                    expector.run_custom_matcher(
+                        &context,
+                        &cloned_name,
+                        &cloned_function,
+                        vec![arg1],
+                    )
+                },
+            );
+
+            let (cloned_name, cloned_function) = (name.clone(), matcher_function.clone());
+            self.engine.register_fn(
+                &name,
+                move |context: NativeCallContext,
+                      expector: &mut Expector,
+                      arg1: Dynamic,
+                      arg2: Dynamic| {
+                    expector.run_custom_matcher(
+                        &context,
+                        &cloned_name,
+                        &cloned_function,
+                        vec![arg1, arg2],
+                    )
+                },
+            );
+
+            let (cloned_name, cloned_function) = (name.clone(), matcher_function.clone());
+            self.engine.register_fn(
+                &name,
+                move |context: NativeCallContext,
+                      expector: &mut Expector,
+                      arg1: Dynamic,
+                      arg2: Dynamic,
+                      arg3: Dynamic| {
+                    expector.run_custom_matcher(
+                        &context,
+                        &cloned_name,
+                        &cloned_function,
+                        vec![arg1, arg2, arg3],
+                    )
+                },
+            );
+        }
+    }
+
+    /// Runs the tests of an evaluated file and returns what should be shown in the terminal for it
+    pub fn run(&mut self, has_only_tests: bool, test_name_pattern: Option<&Regex>) -> String {
+        let suite_start_time = Instant::now();
+
+        match self.evaluated_file.take() {
+            Some(Ok(ast)) => {
+                // We're setting the AST into our shared_ast so that it can be used in our expect functions
+                *self.shared_ast.lock().unwrap() = Some(ast.clone());
+
+                // Custom matchers could only be added while evaluating, so they can now become functions of expect()
+                self.register_custom_matchers();
+
+                // Get the tests (note we're doing it in a let block so that the lock on the test_container only lasts for this block)
+                let tests = {
+                    let container = self.test_container.lock().unwrap();
+                    container.get_tests().clone().to_vec()
+                };
+
+                // Run the tests!
+                let runner: TestRunner = TestRunner::new(
+                    self.test_limiter.clone(),
+                    self.default_limits,
+                    self.test_coverage_container.clone(),
+                );
+                let run_result = runner.run_tests(
+                    &mut self.engine,
+                    &ast,
+                    &self.path,
+                    &tests,
+                    has_only_tests,
+                    test_name_pattern,
+                    self.logging_container.clone(),
+                    self.test_container.clone(),
+                );
+
+                // Update our test container with what passed/failed
+                let mut container = self.test_container.lock().unwrap();
+                container.passed_tests += run_result.passed_tests;
+                container.failed_tests += run_result.failed_tests;
+                container.skipped_tests += run_result.skipped_tests;
+                container.todo_tests += run_result.todo_tests;
+                if run_result.failed_tests > 0 {
+                    container.fail_suite(&self.path);
+                }
+                container.set_suite_results(
+                    &self.path,
+                    run_result.test_results,
+                    suite_start_time.elapsed(),
+                );
+
+                run_result.output
+            }
+            Some(Err((error_output, stack_trace))) => {
+                // Fail the test suite and output the reason
+                let output = format!(
+                    "{} {}\n{}\n",
+                    " FAIL ".white().on_red().bold(),
+                    self.path,
+                    error_output.red()
+                );
+                self.test_container.lock().unwrap().fail_suite_with_error(
+                    &self.path,
+                    error_output,
+                    stack_trace,
+                );
+                output
+            }
+            None => String::new(),
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use rhai::{Dynamic, Engine, EvalAltResult, Map};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Instant;
+
+/// How long a test (or hook) is allowed to run before it is stopped and failed
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TestLimits {
+    /// In milliseconds
+    pub timeout: Option<u64>,
+    pub max_operations: Option<u64>,
+}
+
+impl TestLimits {
+    /// Reads the limits from the options map passed to test(), e.g. `#{ timeout: 100, max_operations: 10000 }`
+    pub fn from_map(options: &Map) -> Result<Self, String> {
+        let mut limits = TestLimits::default();
+
+        for (key, value) in options {
+            let limit = value
+                .as_int()
+                .ok()
+                .filter(|value| *value > 0)
+                .map(|value| value as u64)
+                .ok_or_else(|| format!("Test option '{}' must be a positive number", key))?;
+
+            match key.as_str() {
+                "timeout" => limits.timeout = Some(limit),
+                "max_operations" => limits.max_operations = Some(limit),
+                _ => {
+                    return Err(format!(
+                        "Unknown test option '{}'. Supported options are: timeout, max_operations",
+                        key
+                    ))
+                }
+            }
+        }
+
+        Ok(limits)
+    }
+
+    /// Falls back to the given limits for anything that isn't set
+    pub fn or(self, defaults: TestLimits) -> TestLimits {
+        TestLimits {
+            timeout: self.timeout.or(defaults.timeout),
+            max_operations: self.max_operations.or(defaults.max_operations),
+        }
+    }
+}
+
+/// Enforces the limits of whatever test is currently running. The operation budget is set on the engine with
+/// set_max_operations(), while the timeout is checked from the engine's on_progress callback. It is shared by the test
+/// file's engine and any engine created while its tests run (e.g. by to_throw()), and is lock-free so that scripts run
+/// without limits don't pay for them on every operation.
+#[derive(Debug)]
+pub struct TestLimiter {
+    /// What deadlines are measured from
+    origin: Instant,
+    /// In milliseconds, 0 when what is running has no timeout
+    timeout: AtomicU64,
+    /// When the timeout is up, in milliseconds since `origin`
+    deadline: AtomicU64,
+    /// 0 when what is running has no operation budget
+    max_operations: AtomicU64,
+    timed_out: AtomicBool,
+    exceeded_operations: AtomicBool,
+}
+
+impl TestLimiter {
+    pub fn new() -> Self {
+        Self {
+            origin: Instant::now(),
+            timeout: AtomicU64::new(0),
+            deadline: AtomicU64::new(0),
+            max_operations: AtomicU64::new(0),
+            timed_out: AtomicBool::new(false),
+            exceeded_operations: AtomicBool::new(false),
+        }
+    }
+
+    /// Starts enforcing the limits for what is about to run on the engine
+    pub fn start(&self, engine: &mut Engine, limits: TestLimits) {
+        let timeout = limits.timeout.unwrap_or_default();
+        let max_operations = limits.max_operations.unwrap_or_default();
+        engine.set_max_operations(max_operations);
+
+        self.timed_out.store(false, Ordering::Relaxed);
+        self.exceeded_operations.store(false, Ordering::Relaxed);
+        self.max_operations.store(max_operations, Ordering::Relaxed);
+        self.deadline
+            .store(self.get_elapsed() + timeout, Ordering::Relaxed);
+        self.timeout.store(timeout, Ordering::Relaxed);
+    }
+
+    /// Stops enforcing the limits, returning why the script was stopped if one of them was hit (given the error the
+    /// script ended with, if any)
+    pub fn stop(&self, error: Option<&EvalAltResult>) -> Option<String> {
+        if let Some(error) = error {
+            self.check_error(error);
+        }
+
+        let timeout = self.timeout.swap(0, Ordering::Relaxed);
+        let max_operations = self.max_operations.swap(0, Ordering::Relaxed);
+        if self.timed_out.swap(false, Ordering::Relaxed) {
+            Some(format!("Timed out after {} ms", timeout))
+        } else if self.exceeded_operations.swap(false, Ordering::Relaxed) {
+            Some(format!("Timed out after {} operations", max_operations))
+        } else {
+            None
+        }
+    }
+
+    /// The operation budget of what is running, for engines created while it runs
+    pub fn get_max_operations(&self) -> Option<u64> {
+        Some(self.max_operations.load(Ordering::Relaxed))
+            .filter(|max_operations| *max_operations > 0)
+    }
+
+    /// Checks if a script was stopped for going over its operation budget, which may have happened on another engine
+    pub fn check_error(&self, error: &EvalAltResult) {
+        let mut error = error;
+        while let EvalAltResult::ErrorInFunctionCall(_, _, inner, _) = error {
+            error = inner;
+        }
+
+        if matches!(error, EvalAltResult::ErrorTooManyOperations(..))
+            && self.get_max_operations().is_some()
+        {
+            self.exceeded_operations.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Called by the engine as the script runs. Returning a value terminates the script.
+    pub fn check(&self) -> Option<Dynamic> {
+        if self.timeout.load(Ordering::Relaxed) == 0 {
+            return None;
+        }
+
+        if self.get_elapsed() > self.deadline.load(Ordering::Relaxed) {
+            self.timed_out.store(true, Ordering::Relaxed);
+            return Some(Dynamic::from("Timed out"));
+        }
+
+        None
+    }
+
+    fn get_elapsed(&self) -> u64 {
+        self.origin.elapsed().as_millis() as u64
+    }
+}
@@ -1,12 +1,26 @@
-use super::{logging_container::LoggingContainer, test_container::TestContainer};
-use crate::engine::test_container::Test;
+use super::{
+    error_handling::{get_stack_trace, StackTraceDetail},
+    logging_container::{CapturedLog, LoggingContainer},
+    test_container::TestContainer,
+    test_limits::{TestLimiter, TestLimits},
+};
+use crate::coverage_reporting::test_coverage_container::{CoveringTest, TestCoverageContainer};
+use crate::engine::test_container::{Hook, HookKind, Test, TestMode};
 use colored::*;
-use rhai::{Engine, EvalAltResult, AST};
+use regex::Regex;
+use rhai::{Dynamic, Engine, EvalAltResult, AST};
+use std::fmt::Write;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 pub struct TestSuiteResult {
     pub passed_tests: i32,
     pub failed_tests: i32,
+    pub skipped_tests: i32,
+    pub todo_tests: i32,
+    pub test_results: Vec<TestResult>,
+    /// What to show in the terminal for this suite, buffered so that suites running in parallel don't interleave
+    pub output: String,
 }
 
 impl TestSuiteResult {
@@ -14,123 +28,476 @@ impl TestSuiteResult {
         Self {
             passed_tests: 0,
             failed_tests: 0,
+            skipped_tests: 0,
+            todo_tests: 0,
+            test_results: Vec::new(),
+            output: String::new(),
         }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TestStatus {
+    Passed,
+    Failed,
+    Skipped,
+    Todo,
+}
+
+impl TestStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TestStatus::Passed => "passed",
+            TestStatus::Failed => "failed",
+            TestStatus::Skipped => "skipped",
+            TestStatus::Todo => "todo",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct TestResult {
+    /// The test name prefixed with its describe() groups
     pub name: String,
-    pub is_passed: bool,
+    /// The name the test was declared with
+    pub title: String,
+    pub describe_path: Vec<String>,
+    pub status: TestStatus,
     pub reason: String,
+    pub duration: Duration,
+    /// Logs captured while the test was running
+    pub logs: Vec<CapturedLog>,
+    /// Only populated when the test failed because of an error rather than an expect statement
+    pub stack_trace: Vec<StackTraceDetail>,
 }
 
 impl TestResult {
-    pub fn new(name: String, is_passed: bool, reason: String) -> Self {
+    pub fn new(test: &Test, status: TestStatus, reason: String, duration: Duration) -> Self {
         Self {
-            name,
-            is_passed,
+            name: test.full_name(),
+            title: test.name.clone(),
+            describe_path: test.describe_path.clone(),
+            status,
             reason,
+            duration,
+            logs: Vec::new(),
+            stack_trace: Vec::new(),
         }
     }
+
+    pub fn is_failed(&self) -> bool {
+        self.status == TestStatus::Failed
+    }
 }
 
-pub struct TestRunner {}
+pub struct TestRunner {
+    test_limiter: Arc<TestLimiter>,
+    /// The limits from the config file, used for hooks and for tests that don't set their own
+    default_limits: TestLimits,
+    /// Told which test is running, so that the coverage it collects is attributed to that test
+    test_coverage_container: Arc<Mutex<TestCoverageContainer>>,
+}
 
 impl TestRunner {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(
+        test_limiter: Arc<TestLimiter>,
+        default_limits: TestLimits,
+        test_coverage_container: Arc<Mutex<TestCoverageContainer>>,
+    ) -> Self {
+        Self {
+            test_limiter,
+            default_limits,
+            test_coverage_container,
+        }
     }
 
     pub fn run_tests(
         &self,
-        engine: &Engine,
+        engine: &mut Engine,
         ast: &AST,
         path: &str,
         tests: &Vec<Test>,
+        has_only_tests: bool,
+        test_name_pattern: Option<&Regex>,
         logging_container: Arc<Mutex<LoggingContainer>>,
         test_container: Arc<Mutex<TestContainer>>,
     ) -> TestSuiteResult {
         let mut test_run_result = TestSuiteResult::new();
         let mut test_results = Vec::<TestResult>::new();
-        let mut all_passing = true;
-
-        for test in tests {
-            if test.file_path == path {
-                // Execute the test's function body
-                match test.test_function.call::<()>(engine, ast, ()) {
-                    Ok(_) => {
-                        // Get the results registered by the expect statements and see if we have any errors
-                        let locked_container = test_container.lock().unwrap();
-                        let first_error = locked_container
-                            .expect_results
-                            .iter()
-                            .find_map(|r| r.as_ref().err());
-
-                        // If we have any errors, test failed, otherwise, passed
-                        if first_error.is_some() {
-                            test_results.push(TestResult::new(
-                                test.name.clone(),
-                                false,
-                                first_error.unwrap().to_string(),
-                            ));
-                            test_run_result.failed_tests += 1;
-                            all_passing = false;
-                        } else {
-                            test_results.push(TestResult::new(
-                                test.name.clone(),
-                                true,
-                                "".to_string(),
-                            ));
-                            test_run_result.passed_tests += 1;
+
+        let tests = tests
+            .iter()
+            .filter(|test| test.file_path == path)
+            .collect::<Vec<_>>();
+
+        // Once any test in the run is marked with test_only(), every other test is skipped.
+        // The same goes for tests whose full name doesn't match the --test-name-pattern
+        let should_run = tests
+            .iter()
+            .map(|test| {
+                let is_name_matching = test_name_pattern
+                    .map(|pattern| pattern.is_match(&test.full_name()))
+                    .unwrap_or(true);
+
+                is_name_matching
+                    && match test.mode {
+                        TestMode::Skip | TestMode::Todo => false,
+                        TestMode::Only => true,
+                        TestMode::Normal => !has_only_tests,
+                    }
+            })
+            .collect::<Vec<_>>();
+
+        // Each test runs inside a chain of scopes: the file itself (None) followed by each of its describe() blocks
+        let scopes = tests
+            .iter()
+            .map(|test| {
+                let mut scopes = vec![None];
+                scopes.extend(test.describe_ids.iter().map(|id| Some(*id)));
+                scopes
+            })
+            .collect::<Vec<_>>();
+
+        // Scopes whose before_all hook failed, along with the reason
+        let mut failed_scopes = Vec::<(Option<usize>, String)>::new();
+
+        for (index, test) in tests.iter().enumerate() {
+            let test_scopes = &scopes[index];
+
+            // Skipped tests don't run any hooks either
+            if !should_run[index] {
+                let status = match test.mode {
+                    TestMode::Todo => TestStatus::Todo,
+                    _ => TestStatus::Skipped,
+                };
+                test_results.push(TestResult::new(
+                    test,
+                    status,
+                    "".to_string(),
+                    Duration::ZERO,
+                ));
+                continue;
+            }
+
+            // Run the before_all hooks for any scope we're entering for the first time
+            let previous_scopes = (0..index)
+                .rev()
+                .find(|previous| should_run[*previous])
+                .map(|previous| scopes[previous].as_slice())
+                .unwrap_or_default();
+            for scope in test_scopes
+                .iter()
+                .skip(shared_depth(previous_scopes, test_scopes))
+            {
+                let hooks =
+                    test_container
+                        .lock()
+                        .unwrap()
+                        .get_hooks(path, HookKind::BeforeAll, *scope);
+                if let Err(reason) = self.run_hooks(
+                    engine,
+                    ast,
+                    &hooks,
+                    logging_container.clone(),
+                    test_container.clone(),
+                ) {
+                    failed_scopes.push((*scope, reason));
+                }
+            }
+
+            let start_time = Instant::now();
+
+            let failed_scope_reason = failed_scopes
+                .iter()
+                .find(|(scope, _)| test_scopes.contains(scope))
+                .map(|(_, reason)| reason.clone());
+
+            let mut test_result = match failed_scope_reason {
+                // A before_all hook failed, so we don't even attempt to run this test
+                Some(reason) => {
+                    TestResult::new(test, TestStatus::Failed, reason, start_time.elapsed())
+                }
+                None => {
+                    // Anything covered from here until the after_each hooks are done is covered by this test
+                    self.test_coverage_container
+                        .lock()
+                        .unwrap()
+                        .set_current_test(Some(CoveringTest {
+                            file_path: path.to_string(),
+                            name: test.full_name(),
+                        }));
+
+                    let mut before_each_hooks = Vec::new();
+                    for scope in test_scopes {
+                        before_each_hooks.extend(test_container.lock().unwrap().get_hooks(
+                            path,
+                            HookKind::BeforeEach,
+                            *scope,
+                        ));
+                    }
+
+                    let mut test_result = match self.run_hooks(
+                        engine,
+                        ast,
+                        &before_each_hooks,
+                        logging_container.clone(),
+                        test_container.clone(),
+                    ) {
+                        Ok(()) => self.run_test(engine, ast, path, test, test_container.clone()),
+                        Err(reason) => {
+                            TestResult::new(test, TestStatus::Failed, reason, Duration::ZERO)
                         }
+                    };
+
+                    // after_each hooks run from the innermost scope outwards, even if the test failed
+                    let mut after_each_hooks = Vec::new();
+                    for scope in test_scopes.iter().rev() {
+                        after_each_hooks.extend(test_container.lock().unwrap().get_hooks(
+                            path,
+                            HookKind::AfterEach,
+                            *scope,
+                        ));
                     }
-                    Err(error) => {
-                        let mut reason = error.to_string();
-
-                        match *error {
-                            EvalAltResult::ErrorMismatchOutputType(_, _, _) => {
-                                let hint =
-                                    format!( "{}",
-                                "\n\t\tHint: Make sure your test ends with an expect function."
-                                    .green());
-                                reason.push_str(&hint);
-                            }
-                            _ => (),
+
+                    if let Err(reason) = self.run_hooks(
+                        engine,
+                        ast,
+                        &after_each_hooks,
+                        logging_container.clone(),
+                        test_container.clone(),
+                    ) {
+                        if test_result.status == TestStatus::Passed {
+                            test_result.status = TestStatus::Failed;
+                            test_result.reason = reason;
                         }
+                    }
 
-                        test_results.push(TestResult::new(test.name.clone(), false, reason));
+                    self.test_coverage_container
+                        .lock()
+                        .unwrap()
+                        .set_current_test(None);
 
-                        test_run_result.failed_tests += 1;
-                        all_passing = false;
+                    test_result
+                }
+            };
+            test_result.duration = start_time.elapsed();
+
+            // Keep hold of the logs for reporting before we reset them
+            test_result.logs = logging_container.lock().unwrap().get_logs();
+            test_results.push(test_result);
+
+            // We need to reset some of our containers after each test since these track things on a test-by-test basis and expector functions don't know which test they are running in
+            logging_container.lock().unwrap().reset();
+            test_container.lock().unwrap().clear_expect_results();
+
+            // Run the after_all hooks (innermost first) for any scope we're leaving
+            let next_scopes = (index + 1..tests.len())
+                .find(|next| should_run[*next])
+                .map(|next| scopes[next].as_slice())
+                .unwrap_or_default();
+            for scope in test_scopes
+                .iter()
+                .skip(shared_depth(test_scopes, next_scopes))
+                .rev()
+            {
+                let hooks =
+                    test_container
+                        .lock()
+                        .unwrap()
+                        .get_hooks(path, HookKind::AfterAll, *scope);
+                if let Err(reason) = self.run_hooks(
+                    engine,
+                    ast,
+                    &hooks,
+                    logging_container.clone(),
+                    test_container.clone(),
+                ) {
+                    // The tests in this scope have already run, so fail the ones that passed
+                    for (test_result, result_scopes) in test_results.iter_mut().zip(scopes.iter()) {
+                        if test_result.status == TestStatus::Passed && result_scopes.contains(scope)
+                        {
+                            test_result.status = TestStatus::Failed;
+                            test_result.reason = reason.clone();
+                        }
                     }
                 }
-                // We need to reset some of our containers after each test since these track things on a test-by-test basis and expector functions don't know which test they are running in
-                logging_container.lock().unwrap().reset();
-                test_container.lock().unwrap().clear_expect_results();
             }
         }
 
-        // Did the suite pass?
-        if all_passing {
-            println!("{} {}", " PASS ".white().on_green().bold(), path);
+        let count_status =
+            |status: TestStatus| test_results.iter().filter(|r| r.status == status).count() as i32;
+        test_run_result.passed_tests = count_status(TestStatus::Passed);
+        test_run_result.failed_tests = count_status(TestStatus::Failed);
+        test_run_result.skipped_tests = count_status(TestStatus::Skipped);
+        test_run_result.todo_tests = count_status(TestStatus::Todo);
+
+        // Did the suite pass? (or did it not run anything at all)
+        let output = &mut test_run_result.output;
+        if test_run_result.failed_tests > 0 {
+            writeln!(output, "{} {}", " FAIL ".white().on_red().bold(), path).unwrap();
+        } else if test_run_result.passed_tests == 0 && !test_results.is_empty() {
+            // There's no need to list every test of a suite that didn't run anything
+            writeln!(output, "{} {}", " SKIP ".white().on_yellow().bold(), path).unwrap();
+            test_run_result.test_results = test_results;
+            return test_run_result;
         } else {
-            println!("{} {}", " FAIL ".white().on_red().bold(), path);
+            writeln!(output, "{} {}", " PASS ".white().on_green().bold(), path).unwrap();
         }
 
-        // Output the result of each individual test
-        test_results.iter().for_each(|test_result| {
-            if test_result.is_passed {
-                println!("\t{} {}", "✓".green().bold(), test_result.name);
-            } else {
-                println!(
-                    "\t{} {}\n\t\t{}",
+        // Output the result of each individual test, nested under the describe() groups they belong to
+        let mut previous_describe_path: &[String] = &[];
+        for test_result in &test_results {
+            let describe_path = &test_result.describe_path;
+            let shared_depth = shared_depth(previous_describe_path, describe_path);
+
+            for (depth, group_name) in describe_path.iter().enumerate().skip(shared_depth) {
+                writeln!(output, "{}{}", "\t".repeat(depth + 1), group_name.bold()).unwrap();
+            }
+            previous_describe_path = describe_path;
+
+            let indent = "\t".repeat(describe_path.len() + 1);
+            let line = match test_result.status {
+                TestStatus::Passed => {
+                    format!("{}{} {}", indent, "✓".green().bold(), test_result.title)
+                }
+                TestStatus::Failed => format!(
+                    "{}{} {}\n{}\t{}",
+                    indent,
                     "✗".red().bold(),
-                    test_result.name,
+                    test_result.title,
+                    indent,
                     test_result.reason.red()
-                );
-            }
-        });
+                ),
+                TestStatus::Skipped => {
+                    format!("{}{} {}", indent, "○ skipped".yellow(), test_result.title)
+                }
+                TestStatus::Todo => {
+                    format!("{}{} {}", indent, "✎ todo".magenta(), test_result.title)
+                }
+            };
+            writeln!(output, "{}", line).unwrap();
+        }
+
+        test_run_result.test_results = test_results;
 
         return test_run_result;
     }
+
+    /// Executes a test's function body and checks the results of its expect statements
+    fn run_test(
+        &self,
+        engine: &mut Engine,
+        ast: &AST,
+        path: &str,
+        test: &Test,
+        test_container: Arc<Mutex<TestContainer>>,
+    ) -> TestResult {
+        // Only todo tests are declared without a function, and those never get here
+        let test_function = test.test_function.as_ref().unwrap();
+
+        self.test_limiter
+            .start(engine, test.limits.or(self.default_limits));
+        let result = test_function.call::<()>(engine, ast, ());
+
+        // A test that hit its limits fails with that reason, whatever else happened in it
+        if let Some(reason) = self
+            .test_limiter
+            .stop(result.as_ref().err().map(|error| &**error))
+        {
+            let mut test_result = TestResult::new(test, TestStatus::Failed, reason, Duration::ZERO);
+            if let Err(error) = result {
+                test_result.stack_trace = get_stack_trace(&error, Some(path.to_string()));
+            }
+            return test_result;
+        }
+
+        match result {
+            Ok(_) => {
+                // Get the results registered by the expect statements and see if we have any errors
+                let locked_container = test_container.lock().unwrap();
+                let first_error = locked_container
+                    .expect_results
+                    .iter()
+                    .find_map(|r| r.as_ref().err());
+
+                // If we have any errors, test failed, otherwise, passed
+                match first_error {
+                    Some(error) => {
+                        TestResult::new(test, TestStatus::Failed, error.to_string(), Duration::ZERO)
+                    }
+                    None => {
+                        TestResult::new(test, TestStatus::Passed, "".to_string(), Duration::ZERO)
+                    }
+                }
+            }
+            Err(error) => {
+                let mut reason = error.to_string();
+
+                match *error {
+                    EvalAltResult::ErrorMismatchOutputType(_, _, _) => {
+                        let hint = format!(
+                            "{}",
+                            "\n\t\tHint: Make sure your test ends with an expect function.".green()
+                        );
+                        reason.push_str(&hint);
+                    }
+                    _ => (),
+                }
+
+                let mut test_result =
+                    TestResult::new(test, TestStatus::Failed, reason, Duration::ZERO);
+                test_result.stack_trace = get_stack_trace(&error, Some(path.to_string()));
+                test_result
+            }
+        }
+    }
+
+    /// Runs hooks in order, stopping at the first one that throws or has a failing expect statement.
+    /// The error message says which hook failed so it isn't confused with a failure in the test body.
+    fn run_hooks(
+        &self,
+        engine: &mut Engine,
+        ast: &AST,
+        hooks: &Vec<Hook>,
+        logging_container: Arc<Mutex<LoggingContainer>>,
+        test_container: Arc<Mutex<TestContainer>>,
+    ) -> Result<(), String> {
+        for hook in hooks {
+            self.test_limiter.start(engine, self.default_limits);
+            let result = hook.hook_function.call::<Dynamic>(engine, ast, ());
+            let exceeded_limit = self
+                .test_limiter
+                .stop(result.as_ref().err().map(|error| &**error));
+
+            let mut container = test_container.lock().unwrap();
+            let first_error = container
+                .expect_results
+                .iter()
+                .find_map(|r| r.as_ref().err())
+                .cloned();
+            container.clear_expect_results();
+
+            // before_all and after_all hooks don't belong to a test, so their logs shouldn't show up in one
+            if matches!(hook.kind, HookKind::BeforeAll | HookKind::AfterAll) {
+                logging_container.lock().unwrap().reset();
+            }
+
+            // Hitting a limit takes precedence, as the error it causes doesn't say why the hook was stopped
+            let error = exceeded_limit.or(match result {
+                Err(error) => Some(error.to_string()),
+                Ok(_) => first_error,
+            });
+
+            if let Some(error) = error {
+                return Err(format!("Error in {} hook: {}", hook.kind, error));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// How many leading items two slices have in common
+fn shared_depth<T: PartialEq>(a: &[T], b: &[T]) -> usize {
+    a.iter().zip(b.iter()).take_while(|(a, b)| a == b).count()
 }
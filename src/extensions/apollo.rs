@@ -3,8 +3,8 @@ use apollo_router::_private::rhai as ApolloRhai;
 use apollo_router::plugins::rhai::engine::{
     OptionDance, RhaiRouterFirstRequest, RhaiRouterResponse, SharedMut,
 };
-use apollo_router::Context;
 use apollo_router::services::supergraph;
+use apollo_router::Context;
 use http::HeaderMap;
 use http::Method;
 use rhai::Shared;
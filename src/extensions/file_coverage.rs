@@ -1,5 +1,5 @@
 use crate::coverage_reporting::test_coverage_container::TestCoverageContainer;
-use rhai::Engine;
+use rhai::{Dynamic, Engine};
 use std::sync::{Arc, Mutex};
 
 /// Registers all the instrumentation functions for test coverage
@@ -17,20 +17,52 @@ pub fn register_rhai_functions_and_types(
         };
 
     let test_coverage_container_statements_clone = test_coverage_container.clone();
-    let rhai_test_coverage_instrument_statement = move |source: String, line_number: i64| {
-        test_coverage_container_statements_clone
-            .lock()
-            .unwrap()
-            .statement_called(source, line_number);
-    };
+    let rhai_test_coverage_instrument_statement =
+        move |source: String, line_number: i64, column: i64| {
+            test_coverage_container_statements_clone
+                .lock()
+                .unwrap()
+                .statement_called(source, line_number, column);
+        };
 
     let test_coverage_container_branches_clone = test_coverage_container.clone();
-    let rhai_test_coverage_instrument_branch = move |source: String, line_number: i64| {
-        test_coverage_container_branches_clone
-            .lock()
-            .unwrap()
-            .branch_called(source, line_number);
-    };
+    let rhai_test_coverage_instrument_branch =
+        move |source: String, line_number: i64, column: i64, outcome: i64| {
+            test_coverage_container_branches_clone
+                .lock()
+                .unwrap()
+                .branch_called(source, line_number, column, outcome as usize);
+        };
+
+    // Wraps the condition of an `if`, its outcomes are the condition being true and false
+    let test_coverage_container_conditions_clone = test_coverage_container.clone();
+    let rhai_test_coverage_instrument_condition =
+        move |source: String, line_number: i64, column: i64, value: Dynamic| {
+            if let Ok(condition) = value.as_bool() {
+                test_coverage_container_conditions_clone
+                    .lock()
+                    .unwrap()
+                    .branch_called(source, line_number, column, !condition as usize);
+            }
+            value
+        };
+
+    // Wraps an operand of `??` or what's in front of a `?.`, which branch is taken depends on whether its value is ()
+    let test_coverage_container_values_clone = test_coverage_container.clone();
+    let rhai_test_coverage_instrument_value =
+        move |source: String, line_number: i64, column: i64, outcome: i64, value: Dynamic| {
+            test_coverage_container_values_clone
+                .lock()
+                .unwrap()
+                .branch_value_called(
+                    source,
+                    line_number,
+                    column,
+                    outcome as usize,
+                    value.is_unit(),
+                );
+            value
+        };
 
     engine.register_fn(
         "rhai_test_coverage_instrument_function",
@@ -46,4 +78,14 @@ pub fn register_rhai_functions_and_types(
         "rhai_test_coverage_instrument_branch",
         rhai_test_coverage_instrument_branch,
     );
+
+    engine.register_fn(
+        "rhai_test_coverage_instrument_condition",
+        rhai_test_coverage_instrument_condition,
+    );
+
+    engine.register_fn(
+        "rhai_test_coverage_instrument_value",
+        rhai_test_coverage_instrument_value,
+    );
 }
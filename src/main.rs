@@ -1,25 +1,30 @@
 mod coverage_reporting;
 mod engine;
 mod extensions;
+mod test_reporting;
+mod util;
 use clap::Parser;
 use colored::*;
+use coverage_reporting::coverage_collection::collect_coverage_from;
+use coverage_reporting::coverage_threshold::{check_coverage_thresholds, CoverageThreshold};
+use coverage_reporting::test_attribution::{
+    print_coverage_by_test, print_tests_covering, CoveredLine,
+};
 use coverage_reporting::test_coverage_container::TestCoverageContainer;
-use engine::engine::create_engine;
-use engine::error_handling::{get_stack_trace, get_stack_trace_output};
-use engine::expector::Expector;
-use engine::logging_container::LoggingContainer;
+use coverage_reporting::{write_coverage_results, CoverageReporter};
+use engine::parallel::{run_in_parallel, OrderedPrinter};
 use engine::test_container::TestContainer;
-use engine::test_runner::TestRunner;
+use engine::test_file::TestFile;
 use glob::glob;
 use notify::{EventKind, RecommendedWatcher, RecursiveMode, Watcher};
-use rhai::{Dynamic, FnPtr, Module, ParseError, AST};
+use regex::Regex;
 use serde::Deserialize;
-use std::collections::BTreeMap;
 use std::fs::{self};
-use std::path::{Path, PathBuf};
+use std::path::Path;
 use std::process::{exit, Command};
 use std::sync::{Arc, Mutex};
 use std::time::Instant;
+use test_reporting::{write_results, Reporter};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -29,6 +34,33 @@ struct Args {
 
     #[arg(short, long, action)]
     watch: bool,
+
+    /// Also write the test results in this format (overrides the config file)
+    #[arg(short, long, value_enum)]
+    reporter: Option<Reporter>,
+
+    /// Where to write the reporter output (overrides the config file)
+    #[arg(short, long)]
+    output_file: Option<String>,
+
+    /// Only run tests whose name (including any describe() group prefix) matches this regular expression
+    #[arg(short, long)]
+    test_name_pattern: Option<String>,
+
+    /// How many test files to run at the same time (overrides the config file)
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Print the lines of every script that each test executed (turns coverage on)
+    #[arg(long, action)]
+    coverage_by_test: bool,
+
+    /// List the tests that execute a line of a script, e.g. router.rhai:12 (turns coverage on)
+    #[arg(long, value_name = "FILE:LINE")]
+    tests_covering: Option<String>,
+
+    /// Only run the test files (matched by testMatch) whose path contains one of these paths
+    paths: Vec<String>,
 }
 
 #[derive(Deserialize, Debug, Clone)]
@@ -40,6 +72,50 @@ pub struct Config {
     base_path: String,
 
     coverage: Option<bool>,
+
+    /// Glob patterns of scripts to report coverage for, even when no test imports them
+    #[serde(rename = "collectCoverageFrom")]
+    collect_coverage_from: Option<Vec<String>>,
+
+    #[serde(rename = "coverageReporters")]
+    coverage_reporters: Option<Vec<CoverageReporter>>,
+
+    #[serde(rename = "coverageDirectory")]
+    coverage_directory: Option<String>,
+
+    #[serde(rename = "coverageThreshold")]
+    coverage_threshold: Option<CoverageThreshold>,
+
+    reporter: Option<Reporter>,
+
+    #[serde(rename = "outputFile")]
+    output_file: Option<String>,
+
+    jobs: Option<usize>,
+
+    /// Per-test timeout in milliseconds, tests can override it with `test(name, || {...}, #{ timeout: ... })`
+    #[serde(rename = "testTimeout")]
+    test_timeout: Option<u64>,
+
+    /// Per-test budget of rhai operations, tests can override it with `test(name, || {...}, #{ max_operations: ... })`
+    #[serde(rename = "maxOperations")]
+    max_operations: Option<u64>,
+
+    /// Only set from the command line
+    #[serde(skip)]
+    test_name_pattern: Option<String>,
+
+    /// Only set from the command line
+    #[serde(skip)]
+    test_paths: Vec<String>,
+
+    /// Only set from the command line
+    #[serde(skip)]
+    coverage_by_test: bool,
+
+    /// Only set from the command line
+    #[serde(skip)]
+    tests_covering: Option<CoveredLine>,
 }
 
 fn main() {
@@ -58,7 +134,7 @@ fn main() {
     };
 
     // Parse config file
-    let config: Config = match serde_json::from_str(&config_string) {
+    let mut config: Config = match serde_json::from_str(&config_string) {
         Ok(config_object) => config_object,
         Err(error) => {
             let error_message = format!(
@@ -70,6 +146,46 @@ fn main() {
         }
     };
 
+    // CLI arguments take precedence over the config file
+    if args.reporter.is_some() {
+        config.reporter = args.reporter;
+    }
+    if args.output_file.is_some() {
+        config.output_file = args.output_file;
+    }
+    if args.jobs.is_some() {
+        config.jobs = args.jobs;
+    }
+
+    // Filters only come from the command line
+    if let Some(pattern) = &args.test_name_pattern {
+        if let Err(error) = Regex::new(pattern) {
+            let error_message = format!(
+                "--test-name-pattern is not a valid regular expression. Error: {}",
+                error
+            );
+            println!("{}", error_message.red());
+            exit(99);
+        }
+    }
+    config.test_name_pattern = args.test_name_pattern;
+    config.test_paths = args.paths;
+
+    // Which tests cover what is only known when coverage is collected
+    if let Some(tests_covering) = &args.tests_covering {
+        match CoveredLine::parse(tests_covering) {
+            Ok(covered_line) => config.tests_covering = Some(covered_line),
+            Err(error) => {
+                println!("{}", error.red());
+                exit(99);
+            }
+        }
+    }
+    config.coverage_by_test = args.coverage_by_test;
+    if config.coverage_by_test || config.tests_covering.is_some() {
+        config.coverage = Some(true);
+    }
+
     if args.watch {
         clear_screen();
         run_tests(config.clone(), true);
@@ -128,176 +244,142 @@ fn run_tests(config: Config, is_watch_mode: bool) {
         }
     }
 
-    // Create all our core engine objects
-    let test_container = Arc::new(Mutex::new(TestContainer::new()));
-    let test_coverage_container = Arc::new(Mutex::new(TestCoverageContainer::new()));
+    // Test files are left out of the coverage, even when they're narrowed down below
+    let discovered_test_files = test_files.clone();
+
+    // Narrow the discovered files down to the paths passed on the command line (if any)
+    if !config.test_paths.is_empty() {
+        test_files.retain(|test_file| {
+            config
+                .test_paths
+                .iter()
+                .any(|test_path| matches_test_path(test_file, test_path))
+        });
+    }
+
+    let test_name_pattern = config
+        .test_name_pattern
+        .as_ref()
+        .map(|pattern| Regex::new(pattern).unwrap());
+
+    // Each test file gets its own engine so that several of them can run at the same time
+    let jobs = config.jobs.unwrap_or(1);
     let config_shared = Arc::new(Mutex::new(config));
-    let module_cache = Arc::new(Mutex::new(BTreeMap::<PathBuf, Arc<Module>>::new()));
-    let logging_container = Arc::new(Mutex::new(LoggingContainer::new()));
-    let engine = Arc::new(Mutex::new(create_engine(
-        test_coverage_container.clone(),
-        config_shared.clone(),
-        module_cache.clone(),
-        logging_container.clone(),
-    )));
-    let shared_ast: Arc<Mutex<Option<AST>>> = Arc::new(Mutex::new(None));
-
-    // We're cloning stuff here so that it can be moved into the expect()` closure below
-    let cloned_shared_ast = shared_ast.clone();
-    let test_coverage_container_clone = test_coverage_container.clone();
-    let cloned_config_shared = config_shared.clone();
-    let cloned_module_cache = module_cache.clone();
-    let cloned_logging_container = logging_container.clone();
-    let cloned_container = test_container.clone();
-
-    // Attach the test specific functions to the engine including defining our expect() function
-    {
-        let mut engine_guard = engine.lock().unwrap();
-        engine_guard
-            .register_type_with_name::<Expector>("Expector")
-            .register_fn("expect", move |value: Dynamic| {
-                let mut expector = Expector::new(value);
-                expector.attach(
-                    cloned_shared_ast.clone(),
-                    test_coverage_container_clone.clone(),
-                    cloned_config_shared.clone(),
-                    cloned_module_cache.clone(),
-                    cloned_logging_container.clone(),
-                    cloned_container.clone(),
-                );
-                expector
-            })
-            .register_fn("not", Expector::not)
-            .register_fn("to_be", Expector::to_be)
-            .register_fn("to_exist", Expector::to_exist)
-            .register_fn("to_match", Expector::to_match)
-            .register_fn("to_throw", Expector::to_throw)
-            .register_fn("to_throw_message", Expector::to_throw_message)
-            .register_fn("to_throw_status", Expector::to_throw_status)
-            .register_fn(
-                "to_throw_status_and_message",
-                Expector::to_throw_status_and_message,
-            )
-            .register_fn("to_log", Expector::to_log)
-            .register_fn("to_log_message", Expector::to_log_message);
+    let test_files = test_files
+        .iter()
+        .map(|path| Mutex::new(TestFile::new(path, config_shared.clone())))
+        .collect::<Vec<_>>();
+
+    // First parse and eval each test file, which registers all of its tests.
+    // The tests are only run once every file has been evaluated so that test_only() can apply across the whole run.
+    run_in_parallel(&test_files, jobs, |_, test_file| test_file.evaluate());
+
+    // Then run the tests of each file, printing the results in the same order the files were discovered in
+    let has_only_tests = test_files
+        .iter()
+        .any(|test_file| test_file.lock().unwrap().has_only_tests());
+    let printer = Mutex::new(OrderedPrinter::new());
+    run_in_parallel(&test_files, jobs, |index, test_file| {
+        let output = test_file.run(has_only_tests, test_name_pattern.as_ref());
+        printer.lock().unwrap().print(index, output);
+    });
+
+    // Finally gather the results and coverage of every file
+    let mut test_container = TestContainer::new();
+    let mut test_coverage_container = TestCoverageContainer::new();
+    for test_file in test_files {
+        let test_file = test_file.into_inner().unwrap();
+        test_container.merge(test_file.test_container.lock().unwrap().clone());
+        test_coverage_container.merge(&test_file.test_coverage_container.lock().unwrap());
     }
 
-    // Now parse and eval each test file
-    for path in &test_files {
-        let test_file_content = fs::read_to_string(path).expect("Unable to read rhai test file");
-
-        // Clone these so they can be moved into the test() closure below
-        let cloned_container = test_container.clone();
-        let cloned_logging_container = logging_container.clone();
-        let cloned_path = path.clone();
-
-        // Add the test suite
-        cloned_container.lock().unwrap().add_suite(&path);
-
-        // Create and register our test() function
-        let test = move |test_name: &str, func: FnPtr| {
-            cloned_container
-                .lock()
-                .unwrap()
-                .add_test(test_name, func, &cloned_path);
-        };
-        engine.lock().unwrap().register_fn("test", test);
-
-        // Now we can evaluate our AST
-        let ast: Result<AST, rhai::ParseError> = {
-            let engine_guard = engine.lock().unwrap();
-            engine_guard.compile(&test_file_content)
-        };
-
-        let cloned_container = test_container.clone();
-        match ast {
-            Ok(ast) => {
-                // We're setting the AST into our shared_ast so that it can be used in our expect functions
-                {
-                    let mut ast_lock = shared_ast.lock().unwrap();
-                    *ast_lock = Some(ast.clone());
-                }
+    let (
+        coverage,
+        collect_coverage_patterns,
+        coverage_reporters,
+        coverage_directory,
+        coverage_threshold,
+        base_path,
+        coverage_by_test,
+        tests_covering,
+    ) = {
+        let config = config_shared.lock().unwrap();
+        (
+            config.coverage.unwrap_or_default(),
+            config.collect_coverage_from.clone(),
+            config
+                .coverage_reporters
+                .clone()
+                .unwrap_or(vec![CoverageReporter::Text]),
+            config.coverage_directory.clone(),
+            config.coverage_threshold.clone(),
+            config.base_path.clone(),
+            config.coverage_by_test,
+            config.tests_covering.clone(),
+        )
+    };
 
-                // Now we can evaluate our test file
-                let eval_result = {
-                    let engine_guard = engine.lock().unwrap();
-                    engine_guard.eval::<()>(&test_file_content)
-                };
-                let ast_arc = Arc::new(Mutex::new(ast));
-
-                match eval_result {
-                    Ok(()) => {
-                        // Get the tests (note we're doing it in a let block so that the lock on the test_container only lasts for this block)
-                        let tests = {
-                            let container = test_container.lock().unwrap();
-                            container.get_tests().clone().to_vec()
-                        };
-
-                        // Run the tests!
-                        let runner: TestRunner = TestRunner::new();
-                        let run_result = runner.run_tests(
-                            &engine.lock().unwrap(),
-                            &ast_arc.lock().unwrap(),
-                            &path,
-                            &tests,
-                            cloned_logging_container.clone(),
-                            cloned_container.clone(),
-                        );
-
-                        // Update our test container with what passed/failed
-                        let mut container = test_container.lock().unwrap();
-                        container.passed_tests += run_result.passed_tests;
-                        container.failed_tests += run_result.failed_tests;
-                        if run_result.failed_tests > 0 {
-                            container.fail_suite(&path);
-                        }
-                    }
-                    Err(error) => {
-                        // We failed evaluation... fail the test suite and output the reason
-                        println!("{} {}", " FAIL ".white().on_red().bold(), path);
-                        let stack_trace = get_stack_trace(&error, Some(path.to_string()));
-                        println!(
-                            "{}",
-                            get_stack_trace_output(
-                                "\t\tUnexpected error ocurred when running tests.".to_string(),
-                                &stack_trace,
-                            )
-                            .red()
-                        );
-                        let mut container = test_container.lock().unwrap();
-                        container.fail_suite(&path);
-                    }
-                }
+    // Add the scripts that should be covered, whether or not a test imported them
+    if let Some(patterns) = collect_coverage_patterns.filter(|_| coverage) {
+        match collect_coverage_from(&patterns, &base_path, &discovered_test_files) {
+            Ok((collected_coverage, warnings)) => {
+                warnings
+                    .iter()
+                    .for_each(|warning| println!("{}", warning.yellow()));
+                test_coverage_container.merge(&collected_coverage);
             }
             Err(error) => {
-                // We failed the AST step... fail the test suite and output the reason
-                // Note that to get a proper stack trace, we need to convert the ParseError into a EvalAltResult
-                let ParseError(error_type, position) = error;
-                let rhai_error = rhai::EvalAltResult::ErrorParsing(*error_type, position);
-                println!("{} {}", " FAIL ".white().on_red().bold(), path);
-                let stack_trace = get_stack_trace(&Box::new(rhai_error), Some(path.to_string()));
-                println!(
-                    "{}",
-                    get_stack_trace_output(
-                        "\t\tUnexpected error ocurred when compiling tests.".to_string(),
-                        &stack_trace,
-                    )
-                    .red()
-                );
-                let mut container = test_container.lock().unwrap();
-                container.fail_suite(&path);
+                println!("{}", error.red());
+                if !is_watch_mode {
+                    exit(99);
+                }
             }
         }
     }
     let end_time = Instant::now();
 
     // Print the coverage if we've opted into it
-    if config_shared.lock().unwrap().coverage.unwrap_or_default() {
-        test_coverage_container.lock().unwrap().print_results();
+    if coverage && coverage_reporters.contains(&CoverageReporter::Text) {
+        test_coverage_container.print_results();
+    }
+
+    // Print which tests executed what, if asked for on the command line
+    if coverage_by_test {
+        print_coverage_by_test(&test_coverage_container);
+    }
+    if let Some(covered_line) = &tests_covering {
+        if let Err(error) = print_tests_covering(&test_coverage_container, covered_line) {
+            println!("{}", error.red());
+            if !is_watch_mode {
+                exit(99);
+            }
+        }
+    }
+
+    // Check the coverage against the configured thresholds (if any)
+    let mut is_coverage_threshold_met = true;
+    if let Some(coverage_threshold) = coverage_threshold.filter(|_| coverage) {
+        match check_coverage_thresholds(&coverage_threshold, &test_coverage_container) {
+            Ok(failures) => {
+                if !failures.is_empty() {
+                    is_coverage_threshold_met = false;
+                    println!();
+                    failures
+                        .iter()
+                        .for_each(|failure| println!("{}", failure.red()));
+                }
+            }
+            Err(error) => {
+                println!("{}", error.red());
+                if !is_watch_mode {
+                    exit(99);
+                }
+            }
+        }
     }
 
     // Print the results
-    test_container.lock().unwrap().print_results();
+    test_container.print_results();
 
     let elapsed_time = end_time - start_time;
 
@@ -310,11 +392,60 @@ fn run_tests(config: Config, is_watch_mode: bool) {
 
     println!("Time:        {}", time_string);
 
-    if !is_watch_mode && test_container.lock().unwrap().has_failed_suites() {
+    // Write the results out with the configured reporter (if any)
+    let (reporter, output_file) = {
+        let config = config_shared.lock().unwrap();
+        (config.reporter, config.output_file.clone())
+    };
+    if let Some(reporter) = reporter {
+        match write_results(&reporter, output_file, &test_container, elapsed_time) {
+            Ok(Some(output_file)) => println!("Results:     {}", output_file),
+            Ok(None) => (),
+            Err(error) => {
+                println!("{}", error.red());
+                if !is_watch_mode {
+                    exit(99);
+                }
+            }
+        }
+    }
+
+    // Write the coverage out with any file based coverage reporters
+    if coverage {
+        match write_coverage_results(
+            &coverage_reporters,
+            coverage_directory,
+            &test_coverage_container,
+        ) {
+            Ok(written_files) => written_files
+                .iter()
+                .for_each(|file| println!("Coverage:    {}", file)),
+            Err(error) => {
+                println!("{}", error.red());
+                if !is_watch_mode {
+                    exit(99);
+                }
+            }
+        }
+    }
+
+    if !is_watch_mode && (test_container.has_failed_suites() || !is_coverage_threshold_met) {
         exit(1);
     }
 }
 
+/// Whether a discovered test file falls under a path passed on the command line (either a part of its path or a parent directory)
+fn matches_test_path(test_file: &str, test_path: &str) -> bool {
+    if test_file.contains(test_path.trim_start_matches("./")) {
+        return true;
+    }
+
+    match (fs::canonicalize(test_file), fs::canonicalize(test_path)) {
+        (Ok(test_file), Ok(test_path)) => test_file.starts_with(test_path),
+        _ => false,
+    }
+}
+
 /// Clear the terminal screen completely
 fn clear_screen() {
     if cfg!(target_os = "windows") {
@@ -0,0 +1,174 @@
+use super::strip_colors;
+use crate::engine::{
+    error_handling::StackTraceDetail, logging_container::CapturedLog,
+    test_container::TestContainer, test_runner::TestResult,
+};
+use serde::Serialize;
+use std::time::Duration;
+
+/// Bump this whenever a field is removed, renamed or changes meaning. Adding new fields does not require a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonReport {
+    schema_version: u32,
+    success: bool,
+    num_total_test_suites: usize,
+    num_passed_test_suites: usize,
+    num_failed_test_suites: usize,
+    num_skipped_test_suites: usize,
+    num_total_tests: usize,
+    num_passed_tests: i32,
+    num_failed_tests: i32,
+    num_skipped_tests: i32,
+    num_todo_tests: i32,
+    /// In milliseconds
+    duration: f64,
+    test_suites: Vec<JsonTestSuite>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonTestSuite {
+    path: String,
+    status: &'static str,
+    duration: f64,
+    /// Set when the file could not be compiled or evaluated
+    error: Option<JsonError>,
+    tests: Vec<JsonTest>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonError {
+    message: String,
+    stack_trace: Vec<JsonStackTraceDetail>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonTest {
+    /// The test name prefixed with its describe() groups
+    name: String,
+    title: String,
+    ancestor_titles: Vec<String>,
+    status: &'static str,
+    duration: f64,
+    failure_reason: Option<String>,
+    logs: Vec<JsonLog>,
+    stack_trace: Vec<JsonStackTraceDetail>,
+}
+
+#[derive(Serialize)]
+struct JsonLog {
+    level: String,
+    message: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct JsonStackTraceDetail {
+    message: String,
+    status_code: Option<String>,
+    source: Option<String>,
+    line: Option<usize>,
+    position: Option<usize>,
+}
+
+impl From<&StackTraceDetail> for JsonStackTraceDetail {
+    fn from(detail: &StackTraceDetail) -> Self {
+        Self {
+            message: detail.message.clone(),
+            status_code: Some(detail.status_code.clone()).filter(|s| !s.is_empty()),
+            source: Some(detail.source.clone()).filter(|s| !s.is_empty()),
+            line: detail.position.line(),
+            position: detail.position.position(),
+        }
+    }
+}
+
+impl From<&CapturedLog> for JsonLog {
+    fn from(log: &CapturedLog) -> Self {
+        Self {
+            level: log.level.to_string(),
+            message: log.message.clone(),
+        }
+    }
+}
+
+impl From<&TestResult> for JsonTest {
+    fn from(test_result: &TestResult) -> Self {
+        Self {
+            name: test_result.name.clone(),
+            title: test_result.title.clone(),
+            ancestor_titles: test_result.describe_path.clone(),
+            status: test_result.status.as_str(),
+            duration: to_milliseconds(test_result.duration),
+            failure_reason: Some(strip_colors(&test_result.reason)).filter(|s| !s.is_empty()),
+            logs: test_result.logs.iter().map(JsonLog::from).collect(),
+            stack_trace: test_result
+                .stack_trace
+                .iter()
+                .map(JsonStackTraceDetail::from)
+                .collect(),
+        }
+    }
+}
+
+/// Generates a versioned JSON report of every suite and test, meant to be consumed by other tools
+pub fn generate_report(test_container: &TestContainer, elapsed_time: Duration) -> String {
+    let suites = test_container.get_sorted_suites();
+
+    let test_suites = suites
+        .iter()
+        .map(|suite| JsonTestSuite {
+            path: suite.file_path.clone(),
+            status: if !suite.is_passed {
+                "failed"
+            } else if suite.is_skipped {
+                "skipped"
+            } else {
+                "passed"
+            },
+            duration: to_milliseconds(suite.duration),
+            error: suite.error.as_ref().map(|error| JsonError {
+                message: strip_colors(error).trim().to_string(),
+                stack_trace: suite
+                    .error_stack_trace
+                    .iter()
+                    .map(JsonStackTraceDetail::from)
+                    .collect(),
+            }),
+            tests: suite.test_results.iter().map(JsonTest::from).collect(),
+        })
+        .collect::<Vec<_>>();
+
+    let num_passed_test_suites = suites
+        .iter()
+        .filter(|suite| suite.is_passed && !suite.is_skipped)
+        .count();
+    let num_failed_test_suites = suites.iter().filter(|suite| !suite.is_passed).count();
+
+    let report = JsonReport {
+        schema_version: SCHEMA_VERSION,
+        success: !test_container.has_failed_suites(),
+        num_total_test_suites: suites.len(),
+        num_passed_test_suites,
+        num_failed_test_suites,
+        num_skipped_test_suites: suites.len() - num_passed_test_suites - num_failed_test_suites,
+        num_total_tests: test_container.tests.len(),
+        num_passed_tests: test_container.passed_tests,
+        num_failed_tests: test_container.failed_tests,
+        num_skipped_tests: test_container.skipped_tests,
+        num_todo_tests: test_container.todo_tests,
+        duration: to_milliseconds(elapsed_time),
+        test_suites,
+    };
+
+    serde_json::to_string_pretty(&report).unwrap()
+}
+
+fn to_milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
@@ -0,0 +1,128 @@
+use super::strip_colors;
+use crate::engine::{test_container::TestContainer, test_runner::TestStatus};
+use crate::util::escape_markup;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Generates a JUnit XML report with one <testsuite> per test file and one <testcase> per test()
+/// Suites that failed to compile or evaluate get a single testcase holding an <error>, which is how most CI systems expect it
+pub fn generate_report(test_container: &TestContainer, elapsed_time: Duration) -> String {
+    let suites = test_container.get_sorted_suites();
+    let mut output = String::new();
+
+    let total_tests: usize = suites
+        .iter()
+        .map(|suite| suite.test_results.len().max(suite.error.iter().count()))
+        .sum();
+    let total_failures: usize = suites
+        .iter()
+        .map(|suite| suite.test_results.iter().filter(|r| r.is_failed()).count())
+        .sum();
+    let total_errors = suites.iter().filter(|suite| suite.error.is_some()).count();
+
+    writeln!(output, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+    writeln!(
+        output,
+        r#"<testsuites name="rhai-test" tests="{}" failures="{}" errors="{}" time="{:.3}">"#,
+        total_tests,
+        total_failures,
+        total_errors,
+        elapsed_time.as_secs_f64()
+    )
+    .unwrap();
+
+    for suite in suites {
+        let failures = suite.test_results.iter().filter(|r| r.is_failed()).count();
+        let skipped = suite
+            .test_results
+            .iter()
+            .filter(|r| matches!(r.status, TestStatus::Skipped | TestStatus::Todo))
+            .count();
+        let suite_name = escape_markup(&suite.file_path);
+
+        if let Some(error) = &suite.error {
+            writeln!(
+                output,
+                r#"  <testsuite name="{}" tests="1" failures="0" errors="1" skipped="0" time="{:.3}">"#,
+                suite_name,
+                suite.duration.as_secs_f64()
+            )
+            .unwrap();
+            writeln!(
+                output,
+                r#"    <testcase name="Test suite failed to run" classname="{}" time="0.000">"#,
+                suite_name
+            )
+            .unwrap();
+            write_message_element(&mut output, "error", error);
+            writeln!(output, "    </testcase>").unwrap();
+            writeln!(output, "  </testsuite>").unwrap();
+            continue;
+        }
+
+        writeln!(
+            output,
+            r#"  <testsuite name="{}" tests="{}" failures="{}" errors="0" skipped="{}" time="{:.3}">"#,
+            suite_name,
+            suite.test_results.len(),
+            failures,
+            skipped,
+            suite.duration.as_secs_f64()
+        )
+        .unwrap();
+
+        for test_result in &suite.test_results {
+            let opening_tag = format!(
+                r#"    <testcase name="{}" classname="{}" time="{:.3}""#,
+                escape_markup(&test_result.name),
+                suite_name,
+                test_result.duration.as_secs_f64()
+            );
+
+            match test_result.status {
+                TestStatus::Passed => writeln!(output, "{} />", opening_tag).unwrap(),
+                TestStatus::Failed => {
+                    writeln!(output, "{}>", opening_tag).unwrap();
+                    write_message_element(&mut output, "failure", &test_result.reason);
+                    writeln!(output, "    </testcase>").unwrap();
+                }
+                TestStatus::Skipped => {
+                    writeln!(output, "{}>", opening_tag).unwrap();
+                    writeln!(output, "      <skipped />").unwrap();
+                    writeln!(output, "    </testcase>").unwrap();
+                }
+                TestStatus::Todo => {
+                    writeln!(output, "{}>", opening_tag).unwrap();
+                    writeln!(output, r#"      <skipped message="todo" />"#).unwrap();
+                    writeln!(output, "    </testcase>").unwrap();
+                }
+            }
+        }
+
+        writeln!(output, "  </testsuite>").unwrap();
+    }
+
+    writeln!(output, "</testsuites>").unwrap();
+
+    output
+}
+
+/// Writes a <failure> or <error> element, using the first line of the message as the summary and the whole message as the body
+fn write_message_element(output: &mut String, element: &str, message: &str) {
+    let message = strip_colors(message);
+    let summary = message
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty())
+        .unwrap_or_default();
+
+    writeln!(
+        output,
+        r#"      <{} message="{}">{}</{}>"#,
+        element,
+        escape_markup(summary),
+        escape_markup(message.trim()),
+        element
+    )
+    .unwrap();
+}
@@ -0,0 +1,68 @@
+pub(crate) mod json_reporter;
+pub(crate) mod junit_reporter;
+
+use crate::engine::test_container::TestContainer;
+use clap::ValueEnum;
+use regex::Regex;
+use serde::Deserialize;
+use std::{fs, time::Duration};
+
+/// The different formats test results can be written out in (on top of the terminal output)
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Reporter {
+    /// Only print the results to the terminal
+    Default,
+    /// JUnit XML, understood by most CI systems (GitLab, Jenkins, etc.)
+    Junit,
+    /// Versioned JSON for dashboards and other tooling
+    Json,
+}
+
+impl Reporter {
+    /// Where the report gets written when no output file was configured
+    pub fn default_output_file(&self) -> Option<&'static str> {
+        match self {
+            Reporter::Default => None,
+            Reporter::Junit => Some("junit.xml"),
+            Reporter::Json => Some("rhai-test-results.json"),
+        }
+    }
+}
+
+/// Writes the results stored in the test container with the given reporter.
+/// Returns the path of the file that was written, if the reporter writes one.
+pub fn write_results(
+    reporter: &Reporter,
+    output_file: Option<String>,
+    test_container: &TestContainer,
+    elapsed_time: Duration,
+) -> Result<Option<String>, String> {
+    let output_file = match output_file.or(reporter.default_output_file().map(String::from)) {
+        Some(output_file) => output_file,
+        None => return Ok(None),
+    };
+
+    let report = match reporter {
+        Reporter::Default => return Ok(None),
+        Reporter::Junit => junit_reporter::generate_report(test_container, elapsed_time),
+        Reporter::Json => json_reporter::generate_report(test_container, elapsed_time),
+    };
+
+    fs::write(&output_file, report).map_err(|error| {
+        format!(
+            "Unable to write test results to {}. Error: {}",
+            output_file, error
+        )
+    })?;
+
+    Ok(Some(output_file))
+}
+
+/// Removes the terminal color codes from a message so it can be written to a file
+pub fn strip_colors(message: &str) -> String {
+    Regex::new(r"\x1b\[[0-9;]*m")
+        .unwrap()
+        .replace_all(message, "")
+        .to_string()
+}
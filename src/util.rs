@@ -0,0 +1,16 @@
+/// Escapes the characters that aren't allowed in XML or HTML attributes and text, for the reports written as XML or
+/// HTML. Control characters other than line breaks and tabs aren't allowed in XML at all, so they're left out.
+pub fn escape_markup(value: &str) -> String {
+    value
+        .chars()
+        .filter(|c| !c.is_control() || matches!(c, '\n' | '\r' | '\t'))
+        .map(|c| match c {
+            '&' => "&amp;".to_string(),
+            '<' => "&lt;".to_string(),
+            '>' => "&gt;".to_string(),
+            '"' => "&quot;".to_string(),
+            '\'' => "&#39;".to_string(),
+            _ => c.to_string(),
+        })
+        .collect()
+}